@@ -1,13 +1,20 @@
-use base64::decode;
+use chashmap::CHashMap;
 use http::request::Parts;
 use jsonld::nodemap::{Pointer, Value};
-use kroeg_tap::{sec, EntityStore, StoreError, User};
+use kroeg_tap::{sec, EntityStore, StoreError, StoreItem, User};
 use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Verifier};
 use serde_json::Value as JValue;
 use std::collections::HashMap;
 use std::io::Write;
+use std::time::{Duration, Instant};
 
+use crate::federation::authority_of;
 use crate::jwt::verify;
+use crate::request::Outbound;
+use crate::signature::{self, Signature};
+use crate::state::ServiceState;
+use crate::store::{refetch, FetchBudget};
+use crate::webfinger;
 
 pub fn build_header_magic(parts: &Parts, sig: Vec<String>) -> Vec<u8> {
     let mut result = Vec::new();
@@ -48,90 +55,178 @@ pub fn build_header_magic(parts: &Parts, sig: Vec<String>) -> Vec<u8> {
     result
 }
 
+lazy_static::lazy_static! {
+    /// When each key was last refetched after failing to verify a signature.
+    static ref KEY_REFETCHES: CHashMap<String, Instant> = CHashMap::new();
+}
+
+/// How long to wait before a key can be refetched again.
+const KEY_REFETCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Checks if a key may be refetched, and records the refetch if so. This ensures that
+/// a remote can't make us refetch a key on every request with a bad signature.
+fn may_refetch_key(key_id: &str) -> bool {
+    if KEY_REFETCHES.len() > 10_000 {
+        KEY_REFETCHES.retain(|_, at| at.elapsed() < KEY_REFETCH_INTERVAL);
+    }
+
+    match KEY_REFETCHES.get(key_id) {
+        Some(ref at) if at.elapsed() < KEY_REFETCH_INTERVAL => return false,
+        _ => {}
+    }
+
+    KEY_REFETCHES.insert(key_id.to_owned(), Instant::now());
+    true
+}
+
+/// Verifies a signature against a key object, returning the owner of the key if it matches.
+fn verify_with_key(
+    req: &Parts,
+    key_data: &StoreItem,
+    signature: &Signature,
+) -> Result<Option<String>, StoreError> {
+    let key_pem = if let [Pointer::Value(Value {
+        value: JValue::String(key_pem),
+        ..
+    })] = &key_data.main()[sec!(publicKeyPem)] as &[Pointer]
+    {
+        key_pem
+    } else {
+        return Ok(None);
+    };
+
+    let owner = if let [Pointer::Id(id)] = &key_data.main()[sec!(owner)] as &[Pointer] {
+        id.to_owned()
+    } else {
+        return Ok(None);
+    };
+
+    let key = Rsa::public_key_from_pem(key_pem.as_bytes())?;
+    let key = PKey::from_rsa(key)?;
+
+    // Without an algorithm, the key decides. We only know RSA keys.
+    match signature.algorithm.as_ref().map(String::as_str) {
+        None | Some("rsa-sha256") => {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+            let header_magic = build_header_magic(&req, signature.headers.clone());
+
+            verifier.update(&header_magic)?;
+            if verifier.verify(&signature.signature)? {
+                Ok(Some(owner))
+            } else {
+                Ok(None)
+            }
+        }
+
+        _ => Ok(None),
+    }
+}
+
+/// Finds the key for an `acct:` keyId, by resolving the actor through WebFinger.
+async fn resolve_acct_key(
+    store: &mut dyn EntityStore,
+    outbound: &Outbound,
+    acct: &str,
+) -> Result<Option<String>, StoreError> {
+    let actor = match webfinger::resolve(outbound, acct).await? {
+        Some(actor) => actor,
+        None => return Ok(None),
+    };
+
+    let actor = match store.get(actor, false).await? {
+        Some(actor) => actor,
+        None => return Ok(None),
+    };
+
+    Ok(actor.main()[sec!(publicKey)]
+        .iter()
+        .filter_map(|f| match f {
+            Pointer::Id(id) => Some(id.to_owned()),
+            _ => None,
+        })
+        .next())
+}
+
 pub async fn verify_http_signature(
     req: &Parts,
     store: &mut dyn EntityStore,
+    state: &ServiceState,
 ) -> Result<Option<User>, StoreError> {
-    if let Some(val) = req
-        .headers
-        .get("Signature")
-        .and_then(|f| f.to_str().ok().map(str::to_string))
-    {
-        let values: Vec<_> = val.split(',').map(str::to_string).collect();
-        let mut map = HashMap::new();
-        for value in values {
-            let (name, value) = value.split_at(value.find('=').unwrap() + 1);
-            let name = name.trim_matches('=').to_owned();
-            let value = value.trim_matches('"').to_owned();
-            map.insert(name, value);
+    let signature = match signature::from_request(req) {
+        Some(Ok(signature)) => signature,
+        Some(Err(e)) => {
+            println!(" - bad signature header: {}", e);
+            return Ok(None);
         }
+        None => return Ok(None),
+    };
 
-        match (
-            map.get("keyId").cloned(),
-            map.get("algorithm").cloned(),
-            map.get("headers").cloned(),
-            map.get("signature").cloned(),
-        ) {
-            (Some(key_id), Some(algorithm), Some(headers), Some(signature)) => {
-                let mut key_id_data = key_id.to_owned();
-                if key_id.starts_with("acct:") {
-                    // XXX Mastodon hack, clean this code up later
-                    let spl = key_id.split(':').collect::<Vec<_>>()[1]
-                        .split('@')
-                        .map(String::from)
-                        .collect::<Vec<_>>();
-                    key_id_data = format!("https://{}/users/{}#public-key", spl[1], spl[0]);
-                }
-                let key_data = match store.get(key_id_data, false).await? {
-                    Some(key_data) => key_data,
-                    None => return Ok(None),
-                };
-
-                if let [Pointer::Value(Value {
-                    value: JValue::String(key_pem),
-                    ..
-                })] = &key_data.main()[sec!(publicKeyPem)] as &[Pointer]
-                {
-                    let key = Rsa::public_key_from_pem(key_pem.as_bytes())?;
-                    let key = PKey::from_rsa(key)?;
-                    let signature = decode(signature.as_bytes()).unwrap(); // i know, bad
-                    let owner =
-                        if let [Pointer::Id(id)] = &key_data.main()[sec!(owner)] as &[Pointer] {
-                            id.to_owned()
-                        } else {
-                            return Ok(None);
-                        };
-
-                    match &*algorithm as &str {
-                        "rsa-sha256" => {
-                            let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
-                            let header_magic = build_header_magic(
-                                &req,
-                                headers.split(' ').map(str::to_string).collect(),
-                            );
-
-                            verifier.update(&header_magic).unwrap();
-                            let result = verifier.verify(&signature)?;
-                            if result {
-                                return Ok(Some(User {
-                                    claims: HashMap::new(),
-                                    issuer: Some(key_id.to_owned()),
-                                    subject: owner,
-                                    audience: vec![],
-                                    token_identifier: "http-signature".to_owned(),
-                                }));
-                            }
-                        }
-                        _ => { /* */ }
-                    }
-                }
-            }
+    let key_id = if signature.key_id.starts_with("acct:") {
+        match resolve_acct_key(store, &state.outbound, &signature.key_id).await? {
+            Some(key_id) => key_id,
+            None => return Ok(None),
+        }
+    } else {
+        signature.key_id.to_owned()
+    };
 
-            (_, _, _, _) => { /* */ }
-        };
+    let key_data = match store.get(key_id.to_owned(), false).await? {
+        Some(key_data) => key_data,
+        None => return Ok(None),
+    };
+
+    let mut owner = verify_with_key(req, &key_data, &signature)?;
+
+    // The key may have been rotated since we last saw it, so refetch it once.
+    if owner.is_none() && may_refetch_key(&key_id) {
+        let budget = FetchBudget::of_request(&req.extensions);
+        if let Ok(Some(key_data)) = refetch(store, state, &budget, key_id.to_owned()).await {
+            owner = verify_with_key(req, &key_data, &signature)?;
+        }
     }
 
-    Ok(None)
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(None),
+    };
+
+    if !owns_key(store, &owner, &key_id).await? {
+        println!(
+            " - {} names {} as its owner, who doesn't list it",
+            key_id, owner
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(User {
+        claims: HashMap::new(),
+        issuer: Some(signature.key_id),
+        subject: owner,
+        audience: vec![],
+        token_identifier: "http-signature".to_owned(),
+    }))
+}
+
+/// Checks if an actor owns a key: anyone can publish a key that names them as its owner,
+/// so the key has to live on the same origin, and the actor has to list it as theirs.
+async fn owns_key(
+    store: &mut dyn EntityStore,
+    owner: &str,
+    key_id: &str,
+) -> Result<bool, StoreError> {
+    if authority_of(owner).is_none() || authority_of(owner) != authority_of(key_id) {
+        return Ok(false);
+    }
+
+    let owner = match store.get(owner.to_owned(), false).await? {
+        Some(owner) => owner,
+        None => return Ok(false),
+    };
+
+    Ok(owner.main()[sec!(publicKey)].iter().any(|f| match f {
+        Pointer::Id(id) => id == key_id,
+        _ => false,
+    }))
 }
 
 pub fn anonymous() -> User {
@@ -147,6 +242,7 @@ pub fn anonymous() -> User {
 pub async fn user_from_request(
     req: &Parts,
     store: &mut dyn EntityStore,
+    state: &ServiceState,
 ) -> Result<User, StoreError> {
     if let Some(val) = req
         .headers
@@ -162,7 +258,7 @@ pub async fn user_from_request(
         }
     }
 
-    match verify_http_signature(req, store).await? {
+    match verify_http_signature(req, store, state).await? {
         Some(data) => Ok(data),
         None => Ok(anonymous()),
     }
@@ -0,0 +1,266 @@
+//! An in-memory cache for entity store reads, shared by all requests.
+//!
+//! Other processes writing to the same database don't invalidate this cache, so entries
+//! are only kept for a limited time.
+
+use kroeg_tap::{CollectionPointer, EntityStore, QuadQuery, StoreError, StoreItem};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+
+struct CacheEntry {
+    used: u64,
+    stored: Instant,
+    item: StoreItem,
+}
+
+/// A least-recently-used map of IDs to stored objects.
+struct Lru {
+    capacity: usize,
+    max_age: Duration,
+    tick: u64,
+    entries: HashMap<String, CacheEntry>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn get(&mut self, id: &str) -> Option<StoreItem> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let entry = self.entries.get_mut(id)?;
+        if entry.stored.elapsed() >= self.max_age {
+            self.remove(id);
+            return None;
+        }
+
+        self.order.remove(&entry.used);
+        self.order.insert(tick, id.to_owned());
+        entry.used = tick;
+
+        Some(entry.item.clone())
+    }
+
+    fn insert(&mut self, id: String, item: StoreItem) {
+        self.remove(&id);
+
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+
+            if let Some(id) = self.order.remove(&oldest) {
+                self.entries.remove(&id);
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, id.to_owned());
+        self.entries.insert(
+            id,
+            CacheEntry {
+                used: self.tick,
+                stored: Instant::now(),
+                item,
+            },
+        );
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.order.remove(&entry.used);
+        }
+    }
+}
+
+/// A bounded cache of stored objects. Clones share the same cache.
+#[derive(Clone)]
+pub struct EntityCache(Arc<Mutex<Lru>>);
+
+impl EntityCache {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        EntityCache(Arc::new(Mutex::new(Lru {
+            capacity,
+            max_age,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        })))
+    }
+
+    /// Creates a cache as configured, or None if caching is disabled.
+    pub fn from_config(config: &CacheConfig) -> Option<Self> {
+        if config.capacity == 0 {
+            None
+        } else {
+            Some(EntityCache::new(
+                config.capacity,
+                Duration::from_secs(config.max_age),
+            ))
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<StoreItem> {
+        self.0.lock().unwrap().get(id)
+    }
+
+    pub fn insert(&self, id: String, item: StoreItem) {
+        self.0.lock().unwrap().insert(id, item)
+    }
+
+    pub fn invalidate(&self, id: &str) {
+        self.0.lock().unwrap().remove(id)
+    }
+}
+
+/// An entity store that keeps the objects it reads in an `EntityCache`.
+/// Without a cache, all calls go straight to the inner store.
+pub struct CachingEntityStore<T> {
+    store: T,
+    cache: Option<EntityCache>,
+}
+
+impl<T: EntityStore> CachingEntityStore<T> {
+    pub fn new(store: T, cache: Option<EntityCache>) -> Self {
+        CachingEntityStore { store, cache }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: EntityStore> EntityStore for CachingEntityStore<T> {
+    async fn get(&mut self, path: String, local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.store.get(path, local).await,
+        };
+
+        if let Some(item) = cache.get(&path) {
+            return Ok(Some(item));
+        }
+
+        let item = self.store.get(path.clone(), local).await?;
+        if let Some(item) = &item {
+            cache.insert(path, item.clone());
+        }
+
+        Ok(item)
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&path);
+        }
+
+        self.store.put(path, item).await
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        self.store.query(query).await
+    }
+
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        self.store.read_collection(path, count, cursor).await
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        self.store.find_collection(path, item).await
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.store.insert_collection(path, item).await
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        self.store.read_collection_inverse(item).await
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.store.remove_collection(path, item).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kroeg_tap::as2;
+    use serde_json::json;
+
+    fn insert(cache: &EntityCache, id: &str) {
+        let item = StoreItem::parse(id, &json!({ "@id": id, "@type": [as2!(Note)] })).unwrap();
+        cache.insert(id.to_owned(), item);
+    }
+
+    fn check_consistency(cache: &EntityCache) {
+        let lru = cache.0.lock().unwrap();
+
+        assert!(lru.entries.len() <= lru.capacity);
+        assert_eq!(lru.entries.len(), lru.order.len());
+        for (used, id) in &lru.order {
+            assert_eq!(lru.entries[id].used, *used);
+        }
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_object() {
+        let cache = EntityCache::new(2, Duration::from_secs(60));
+        insert(&cache, "https://a.example/1");
+        insert(&cache, "https://a.example/2");
+
+        // Reading the first object makes the second one the least recently used.
+        assert!(cache.get("https://a.example/1").is_some());
+        insert(&cache, "https://a.example/3");
+        check_consistency(&cache);
+
+        assert!(cache.get("https://a.example/1").is_some());
+        assert!(cache.get("https://a.example/2").is_none());
+        assert!(cache.get("https://a.example/3").is_some());
+        check_consistency(&cache);
+    }
+
+    #[test]
+    fn replaces_objects_without_evicting_others() {
+        let cache = EntityCache::new(2, Duration::from_secs(60));
+        insert(&cache, "https://a.example/1");
+        insert(&cache, "https://a.example/2");
+        insert(&cache, "https://a.example/1");
+        check_consistency(&cache);
+
+        assert!(cache.get("https://a.example/1").is_some());
+        assert!(cache.get("https://a.example/2").is_some());
+    }
+
+    #[test]
+    fn forgets_invalidated_and_expired_objects() {
+        let cache = EntityCache::new(2, Duration::from_secs(60));
+        insert(&cache, "https://a.example/1");
+        insert(&cache, "https://a.example/2");
+
+        cache.invalidate("https://a.example/1");
+        cache.invalidate("https://a.example/unknown");
+        check_consistency(&cache);
+
+        assert!(cache.get("https://a.example/1").is_none());
+        assert!(cache.get("https://a.example/2").is_some());
+
+        let expiring = EntityCache::new(2, Duration::from_secs(0));
+        insert(&expiring, "https://a.example/1");
+
+        assert!(expiring.get("https://a.example/1").is_none());
+        check_consistency(&expiring);
+    }
+}
@@ -0,0 +1,315 @@
+//! The HTTP clients used for outbound requests: retrieving objects, WebFinger lookups
+//! and deliveries.
+//!
+//! Every service has its own client, as passed to `KroegService::new`, e.g. a `SurfClient`,
+//! or a `MockClient` in tests.
+
+use futures::io::AsyncReadExt;
+use http::{HeaderMap, Method, StatusCode};
+use kroeg_tap::StoreError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::request::FetchError;
+
+pub type ClientRequest = http::Request<Vec<u8>>;
+pub type ClientResponse = http::Response<Vec<u8>>;
+
+/// Sends outbound HTTP requests.
+#[async_trait::async_trait]
+pub trait HttpClient: Send + Sync {
+    /// Sends a request. At most `max_size` bytes of the response body are read, larger
+    /// responses are an error.
+    async fn send(
+        &self,
+        request: ClientRequest,
+        max_size: usize,
+    ) -> Result<ClientResponse, StoreError>;
+}
+
+/// The response headers that are passed on from surf.
+const RESPONSE_HEADERS: &[&str] = &["Content-Type", "Content-Length", "Location", "Retry-After"];
+
+/// The HTTP client that actually talks to the network.
+#[derive(Debug)]
+pub struct SurfClient;
+
+#[async_trait::async_trait]
+impl HttpClient for SurfClient {
+    async fn send(
+        &self,
+        request: ClientRequest,
+        max_size: usize,
+    ) -> Result<ClientResponse, StoreError> {
+        let (parts, body) = request.into_parts();
+
+        let mut request = surf::Request::new(parts.method, parts.uri.to_string().parse()?);
+        for (name, value) in &parts.headers {
+            request = request.set_header(name.as_str(), value.to_str()?);
+        }
+
+        if !body.is_empty() {
+            request = request.body_bytes(body);
+        }
+
+        let mut response = request.await?;
+
+        let length = response
+            .header("Content-Length")
+            .and_then(|f| f.parse::<usize>().ok());
+        if length.map(|f| f > max_size) == Some(true) {
+            return Err(FetchError::TooLarge.into());
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            let read = response.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+
+            if body.len() + read > max_size {
+                return Err(FetchError::TooLarge.into());
+            }
+
+            body.extend_from_slice(&buf[..read]);
+        }
+
+        let mut builder = http::Response::builder();
+        builder.status(response.status());
+
+        for name in RESPONSE_HEADERS {
+            if let Some(value) = response.header(name) {
+                builder.header(*name, value);
+            }
+        }
+
+        Ok(builder.body(body)?)
+    }
+}
+
+/// A request as seen by a `MockClient`.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|f| f.to_str().ok())
+    }
+}
+
+/// A recorded response, as stored in a fixture file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockResponse {
+    pub status: u16,
+
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub body: String,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        MockResponse {
+            status,
+            headers: BTreeMap::new(),
+            body: String::new(),
+        }
+    }
+
+    /// An ActivityStreams document.
+    pub fn json(value: &serde_json::Value) -> Self {
+        MockResponse::new(200)
+            .header("Content-Type", "application/activity+json")
+            .body(value.to_string())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn body<S: Into<String>>(mut self, body: S) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    fn to_response(&self) -> Result<ClientResponse, StoreError> {
+        let mut builder = http::Response::builder();
+        builder.status(StatusCode::from_u16(self.status)?);
+
+        for (name, value) in &self.headers {
+            builder.header(name as &str, value as &str);
+        }
+
+        Ok(builder.body(self.body.as_bytes().to_vec())?)
+    }
+
+    fn from_response(response: &ClientResponse) -> Self {
+        MockResponse {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|value| (name.as_str().to_owned(), value.to_owned()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(response.body()).into_owned(),
+        }
+    }
+}
+
+/// One request and the response to it, as stored in a fixture file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    response: MockResponse,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// The responses to give, by method and URL. The last response for a URL is repeated.
+    responses: HashMap<(String, String), VecDeque<MockResponse>>,
+    requests: Vec<RecordedRequest>,
+    exchanges: Vec<Exchange>,
+}
+
+/// An HTTP client that answers with prepared responses, and remembers every request.
+///
+/// It can also pass requests on to another client and record the responses, which can
+/// then be saved as a fixture file and replayed later without the network.
+#[derive(Clone, Default)]
+pub struct MockClient {
+    state: Arc<Mutex<MockState>>,
+    inner: Option<Arc<dyn HttpClient>>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a client that sends all requests with `inner`, recording the responses.
+    pub fn recording(inner: Arc<dyn HttpClient>) -> Self {
+        MockClient {
+            state: Default::default(),
+            inner: Some(inner),
+        }
+    }
+
+    /// Creates a client that replays the responses in a fixture file.
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let exchanges: Vec<Exchange> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let client = MockClient::new();
+
+        for exchange in exchanges {
+            let method: Method = exchange.method.parse()?;
+            client.respond(method, &exchange.url, exchange.response);
+        }
+
+        Ok(client)
+    }
+
+    /// Saves the recorded responses as a fixture file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), StoreError> {
+        let state = self.state.lock().unwrap();
+        std::fs::write(path, serde_json::to_vec_pretty(&state.exchanges)?)?;
+
+        Ok(())
+    }
+
+    /// Adds a response for a request. Multiple responses for the same request are given
+    /// in order, and the last one is repeated.
+    pub fn respond(&self, method: Method, url: &str, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry((method.as_str().to_owned(), url.to_owned()))
+            .or_insert_with(VecDeque::new)
+            .push_back(response);
+    }
+
+    /// Returns all requests sent so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the requests sent so far to a URL.
+    pub fn requests_to(&self, url: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|f| f.url == url)
+            .collect()
+    }
+
+    fn next_response(&self, method: &Method, url: &str) -> Option<MockResponse> {
+        let mut state = self.state.lock().unwrap();
+        let responses = state
+            .responses
+            .get_mut(&(method.as_str().to_owned(), url.to_owned()))?;
+
+        if responses.len() > 1 {
+            responses.pop_front()
+        } else {
+            responses.front().cloned()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpClient for MockClient {
+    async fn send(
+        &self,
+        request: ClientRequest,
+        max_size: usize,
+    ) -> Result<ClientResponse, StoreError> {
+        let method = request.method().clone();
+        let url = request.uri().to_string();
+
+        self.state.lock().unwrap().requests.push(RecordedRequest {
+            method: method.clone(),
+            url: url.to_owned(),
+            headers: request.headers().clone(),
+            body: request.body().clone(),
+        });
+
+        if let Some(inner) = &self.inner {
+            let response = inner.send(request, max_size).await?;
+
+            self.state.lock().unwrap().exchanges.push(Exchange {
+                method: method.as_str().to_owned(),
+                url,
+                response: MockResponse::from_response(&response),
+            });
+
+            return Ok(response);
+        }
+
+        let response = match self.next_response(&method, &url) {
+            Some(response) => response.to_response()?,
+            None => return Err(format!("no mock response for {} {}", method, url).into()),
+        };
+
+        if response.body().len() > max_size {
+            return Err(FetchError::TooLarge.into());
+        }
+
+        Ok(response)
+    }
+}
@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const AS2: &str = "https://www.w3.org/ns/activitystreams#";
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServerConfig {
@@ -7,4 +11,302 @@ pub struct ServerConfig {
     pub description: String,
     pub instance_id: u32,
     pub admins: Vec<String>,
+
+    #[serde(default)]
+    pub fetch: FetchConfig,
+
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+
+    #[serde(default)]
+    pub federation: FederationConfig,
+
+    #[serde(default)]
+    pub cache: CacheConfig,
+
+    #[serde(default)]
+    pub post: PostConfig,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+/// The in-memory cache for objects read from the store, shared by all requests.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// The most objects kept in the cache. 0 disables the cache.
+    pub capacity: usize,
+
+    /// How long an object is kept in the cache, in seconds.
+    pub max_age: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 0,
+            max_age: 60,
+        }
+    }
+}
+
+/// Configuration for POST requests to inboxes and outboxes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PostConfig {
+    /// How long the response to an outbox POST with an `Idempotency-Key` header is
+    /// replayed for repeats of that key by the same user, in seconds.
+    pub idempotency_window: u64,
+
+    /// The largest request body accepted by inboxes and outboxes, in bytes.
+    pub max_body_size: usize,
+}
+
+impl Default for PostConfig {
+    fn default() -> Self {
+        PostConfig {
+            idempotency_window: 24 * 60 * 60,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// A token bucket: it holds up to `burst` requests, and refills at `per_minute`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+
+    /// How many requests are allowed per minute. 0 disables the limit.
+    pub per_minute: u32,
+}
+
+/// Rate limits for incoming requests. Every limit applies separately to the authenticated
+/// subject, the domain that signed the request, and the IP address of the client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// GET requests, which may make the server retrieve remote objects.
+    pub read: RateLimit,
+
+    /// POSTs to inboxes, and any other POSTs that aren't to an outbox.
+    pub inbox: RateLimit,
+
+    /// POSTs to outboxes.
+    pub outbox: RateLimit,
+
+    /// The header with the IP address of the client, as set by a reverse proxy, like
+    /// `X-Forwarded-For`. The last address in it is used. This has to be set behind a
+    /// proxy, as all requests would come from its address otherwise. It is empty by
+    /// default, which doesn't limit requests by IP address: without a proxy, clients
+    /// could send any address they like in the header. Unsigned requests without a token
+    /// are only limited by IP address.
+    pub ip_header: String,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            read: RateLimit {
+                burst: 600,
+                per_minute: 600,
+            },
+            inbox: RateLimit {
+                burst: 600,
+                per_minute: 600,
+            },
+            outbox: RateLimit {
+                burst: 60,
+                per_minute: 60,
+            },
+            ip_header: String::new(),
+        }
+    }
+}
+
+/// How the server treats other servers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    /// Require GET requests for actors and objects to be signed ("secure mode").
+    /// Unsigned requests only get the public key of an actor.
+    pub authorized_fetch: bool,
+
+    /// Domains whose requests are refused, even for public content. The same as
+    /// setting the `reject` policy for these domains.
+    pub blocked_domains: Vec<String>,
+
+    /// Only federate with the domains in `allowed_domains`.
+    pub allowlist_only: bool,
+
+    /// The domains to federate with if `allowlist_only` is set.
+    pub allowed_domains: Vec<String>,
+
+    /// Policies for specific domains. A domain also matches all of its subdomains,
+    /// unless the subdomain has a policy of its own.
+    pub domains: HashMap<String, DomainPolicy>,
+}
+
+/// A policy for a remote domain.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainPolicy {
+    /// Don't accept, retrieve, or deliver anything from or to this domain.
+    Reject,
+
+    /// Accept activities, but drop any attachments, icons and images.
+    RejectMedia,
+
+    /// Only accept activities into the inboxes of users that follow the actor.
+    Silence,
+}
+
+impl FederationConfig {
+    /// Returns the policy for a domain, using the most specific domain it matches.
+    pub fn domain_policy(&self, host: &str) -> Option<DomainPolicy> {
+        if matches_host(&self.blocked_domains, host) {
+            return Some(DomainPolicy::Reject);
+        }
+
+        let host = normalize_host(host);
+
+        self.domains
+            .iter()
+            .map(|(domain, policy)| (normalize_host(domain), *policy))
+            .filter(|(domain, _)| is_same_or_subdomain(&host, domain))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, policy)| policy)
+    }
+
+    /// Checks if nothing should be exchanged with a domain at all.
+    pub fn is_rejected(&self, host: &str) -> bool {
+        (self.allowlist_only && !matches_host(&self.allowed_domains, host))
+            || self.domain_policy(host) == Some(DomainPolicy::Reject)
+    }
+}
+
+/// The policy for outbound requests, to ensure remote documents can't make us
+/// request anything on our internal network.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// Allow plain HTTP requests. Only useful for development.
+    pub allow_http: bool,
+
+    /// Hosts that may be requested even if they resolve to a private address.
+    /// A host also matches all of its subdomains.
+    pub allow_hosts: Vec<String>,
+
+    /// Hosts that are never requested. A host also matches all of its subdomains.
+    pub deny_hosts: Vec<String>,
+
+    /// The largest response body that is read, in bytes.
+    pub max_response_size: usize,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            allow_http: false,
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            max_response_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl OutboundConfig {
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        matches_host(&self.allow_hosts, host)
+    }
+
+    pub fn is_denied_host(&self, host: &str) -> bool {
+        matches_host(&self.deny_hosts, host)
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// Checks if a normalized host is the domain, or one of its subdomains.
+fn is_same_or_subdomain(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// Checks if a host is in the list, or is a subdomain of one in the list.
+pub fn matches_host(list: &[String], host: &str) -> bool {
+    let host = normalize_host(host);
+
+    list.iter()
+        .any(|f| is_same_or_subdomain(&host, &normalize_host(f)))
+}
+
+/// Configuration for retrieving remote objects.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    /// How long a retrieved object is considered fresh, in seconds.
+    pub default_ttl: u64,
+
+    /// Per-type overrides of `default_ttl`. Types are either full IRIs,
+    /// or names in the ActivityStreams namespace, like `Person`.
+    pub ttl: HashMap<String, u64>,
+
+    /// The most remote objects retrieved while handling a single request.
+    pub max_fetches: u32,
+
+    /// How far references are followed from the objects a request starts with.
+    /// Objects referenced by an object retrieved at depth `n` are at depth `n + 1`.
+    pub max_depth: u32,
+
+    /// How long a single request may spend retrieving remote objects, in seconds. Retrievals
+    /// that are still running when this runs out are cancelled.
+    pub max_duration: u64,
+
+    /// The most items of a local collection that an activity is delivered to.
+    pub max_recipients: u32,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        let mut ttl = HashMap::new();
+        for actor in &["Person", "Service", "Application", "Group", "Organization"] {
+            ttl.insert(actor.to_string(), 24 * 60 * 60);
+        }
+
+        ttl.insert("https://w3id.org/security#Key".to_owned(), 24 * 60 * 60);
+
+        FetchConfig {
+            default_ttl: 7 * 24 * 60 * 60,
+            ttl,
+            max_fetches: 50,
+            max_depth: 5,
+            max_duration: 30,
+            max_recipients: 100_000,
+        }
+    }
+}
+
+impl FetchConfig {
+    /// Returns how long an object with these types is considered fresh.
+    /// If multiple types have a TTL configured, the shortest one is used.
+    pub fn ttl_for(&self, types: &[String]) -> Duration {
+        let secs = types
+            .iter()
+            .filter_map(|kind| {
+                self.ttl.get(kind).or_else(|| {
+                    if kind.starts_with(AS2) {
+                        self.ttl.get(&kind[AS2.len()..])
+                    } else {
+                        None
+                    }
+                })
+            })
+            .min()
+            .cloned()
+            .unwrap_or(self.default_ttl);
+
+        Duration::from_secs(secs)
+    }
 }
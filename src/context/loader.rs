@@ -1,18 +1,65 @@
 use chashmap::CHashMap;
 use jsonld::RemoteContextLoader;
 use serde_json::Value;
+use std::cell::RefCell;
 use std::error::Error;
 use std::fmt::{self, Display};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 
-use crate::request::do_request;
+use crate::client::SurfClient;
+use crate::config::OutboundConfig;
+use crate::request::{do_request, Outbound};
 
 lazy_static::lazy_static! {
     /// List of contexts that have already been read.
     static ref CONTEXT_MAP: CHashMap<String, Value> = CHashMap::new();
 }
 
+thread_local! {
+    /// The outbound of the service whose future is being polled on this thread.
+    static OUTBOUND: RefCell<Option<Outbound>> = RefCell::new(None);
+}
+
+/// Adds a context as if it had been read from `url`, so it is never retrieved.
+pub fn preload(url: &str, context: Value) {
+    CONTEXT_MAP.insert(url.to_owned(), context);
+}
+
+/// A future that retrieves the contexts it needs with the outbound of a service.
+pub struct WithOutbound<F> {
+    outbound: Outbound,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithOutbound<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let outer = OUTBOUND.with(|f| f.replace(Some(this.outbound.clone())));
+        let result = this.future.as_mut().poll(cx);
+        OUTBOUND.with(|f| f.replace(outer));
+
+        result
+    }
+}
+
+/// Runs a future, retrieving the contexts it needs with the client, policy and key of a
+/// service. `SurfContextLoader` can't be given any state, so the outbound is set around
+/// every poll of the future instead.
+///
+/// Contexts loaded outside of such a future are retrieved with a plain `SurfClient`, the
+/// default outbound policy and no signature.
+pub fn with_outbound<F: Future>(outbound: Outbound, future: F) -> WithOutbound<F> {
+    WithOutbound {
+        outbound,
+        future: Box::pin(future),
+    }
+}
+
 #[derive(Debug)]
 pub struct SurfContextLoader;
 
@@ -35,12 +82,22 @@ impl RemoteContextLoader for SurfContextLoader {
     type Future = Pin<Box<dyn Future<Output = Result<Value, Self::Error>> + Send + 'static>>;
 
     fn load_context(url: String) -> Self::Future {
+        let outbound = OUTBOUND
+            .with(|f| f.borrow().clone())
+            .unwrap_or_else(|| Outbound {
+                client: Arc::new(SurfClient),
+                policy: OutboundConfig::default(),
+                key: None,
+            });
+
         Box::pin(async move {
             if let Some(val) = CONTEXT_MAP.get(&url) {
                 return Ok(val.clone());
             }
 
-            let response: Value = do_request(&url).await.map_err(ContextLoadError)?;
+            let response: Value = do_request(&outbound, &url)
+                .await
+                .map_err(ContextLoadError)?;
             eprintln!(" [ ] Loaded context at: {}", url);
             CONTEXT_MAP.insert(url, response.clone());
 
@@ -1,5 +1,6 @@
 use futures::future::FutureExt;
 use futures_timer::{Delay, TryFutureExt};
+use http::StatusCode;
 use http_service::Body;
 use jsonld::nodemap::{Pointer, Value};
 use jsonld::{compact, error::CompactionError, JsonLdOptions};
@@ -7,18 +8,30 @@ use kroeg_tap::{
     as2, assemble, kroeg, sec, Context, DefaultAuthorizer, LocalOnlyAuthorizer, QueueItem,
 };
 use kroeg_tap::{StoreError, StoreItem};
-use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Signer};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sign::Signer,
+};
 use serde_json::{json, Value as JValue};
 use sha2::{Digest, Sha256};
 use std::collections::HashSet;
-use std::fmt::Debug;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
 
+use crate::client::ClientRequest;
 use crate::context;
 use crate::post;
+use crate::request::check_url;
 use crate::router::RequestHandler;
+use crate::state;
+use crate::store::{refetch, FetchBudget};
 use crate::ServerError;
 
+/// The largest response to a delivery that is read, in bytes. Only the status is used.
+const MAX_DELIVERY_RESPONSE: usize = 1024 * 1024;
+
 pub fn escape(s: &str) -> String {
     s.replace("\\", "\\\\").replace(" ", "\\s")
 }
@@ -40,15 +53,16 @@ pub async fn compact_with_context(
     .await
 }
 
-pub fn create_signature<C: surf::middleware::HttpClient + Debug + Unpin + Send + Sync>(
+pub fn create_signature(
     data: &str,
     key_object: &StoreItem,
-    req: surf::Request<C>,
-) -> Result<surf::Request<C>, Box<dyn std::error::Error + Send + Sync>> {
+    mut req: ClientRequest,
+) -> Result<ClientRequest, Box<dyn std::error::Error + Send + Sync>> {
     let digest = Sha256::digest_str(data);
     let digest = base64::encode_config(&digest, base64::STANDARD);
 
-    let mut req = req.set_header("digest", format!("SHA-256={}", digest));
+    req.headers_mut()
+        .insert("digest", format!("SHA-256={}", digest).parse()?);
 
     let private_key = if let [Pointer::Value(Value {
         value: JValue::String(strval),
@@ -60,24 +74,46 @@ pub fn create_signature<C: surf::middleware::HttpClient + Debug + Unpin + Send +
         return Ok(req);
     };
 
-    let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+    sign_request(
+        key_object.id(),
+        &private_key,
+        &["(request-target)", "host", "digest"],
+        req,
+    )
+}
+
+/// Signs a request with the given key. All headers that are signed, except for
+/// `(request-target)` and `host`, have to be set on the request already.
+pub fn sign_request(
+    key_id: &str,
+    private_key: &PKey<Private>,
+    headers: &[&str],
+    mut req: ClientRequest,
+) -> Result<ClientRequest, Box<dyn std::error::Error + Send + Sync>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), private_key)?;
 
     let mut signed = String::new();
-    let headers = &["(request-target)", "host", "digest"];
     for val in headers {
         let value = match *val {
             "(request-target)" => format!(
                 "(request-target): {} {}{}",
                 req.method().as_str().to_lowercase(),
-                req.url().path(),
-                match req.url().query() {
+                req.uri().path(),
+                match req.uri().query() {
                     None => format!(""),
                     Some(val) => format!("?{}", val),
                 }
             ),
 
-            "host" => format!("{}: {}", val, req.url().host().unwrap()),
-            val => format!("{}: {}", val, req.headers().get(val).unwrap_or("")),
+            "host" => format!("{}: {}", val, req.uri().host().unwrap_or("")),
+            val => format!(
+                "{}: {}",
+                val,
+                req.headers()
+                    .get(val)
+                    .and_then(|f| f.to_str().ok())
+                    .unwrap_or("")
+            ),
         };
 
         if signed.len() > 0 {
@@ -90,20 +126,60 @@ pub fn create_signature<C: surf::middleware::HttpClient + Debug + Unpin + Send +
     signer.update(signed.as_bytes())?;
     let signature = base64::encode_config(&signer.sign_to_vec()?, base64::STANDARD);
 
-    Ok(req.set_header(
-        "Signature",
+    req.headers_mut().insert(
+        "signature",
         format!(
             "keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
-            key_object.id(),
+            key_id,
             headers.join(" "),
             signature
-        ),
-    ))
+        )
+        .parse()?,
+    );
+
+    Ok(req)
+}
+
+/// Formats a time as used in the Date header, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs();
+    let days = secs / 86400;
+
+    // Converts days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        (secs % 86400) / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
 }
 
 pub async fn deliver_one(
     context: &mut Context<'_, '_>,
     item: &QueueItem,
+    budget: &FetchBudget,
 ) -> Result<(), ServerError> {
     match &item.event as &str {
         "deliver" => {
@@ -166,11 +242,13 @@ pub async fn deliver_one(
 
             if is_local {
                 let handler = post::PostHandler;
-                let req = http::Request::builder()
+                let mut req = http::Request::builder()
                     .uri(&inbox)
                     .method("POST")
+                    .header("Content-Type", "application/activity+json")
                     .body(Body::from(object.to_string()))
                     .unwrap();
+                req.extensions_mut().insert(budget.clone());
                 let response = handler.run(context, req).await?;
 
                 println!(
@@ -180,7 +258,23 @@ pub async fn deliver_one(
                     response.status()
                 );
             } else {
-                let mut request = surf::post(&inbox);
+                let state = state::of(context)?;
+                let inbox_url =
+                    Url::parse(&inbox).map_err(|e| ServerError::HandlerError(e.into()))?;
+                check_url(&state.outbound.policy, &inbox_url)
+                    .await
+                    .map_err(|e| ServerError::HandlerError(e.into()))?;
+
+                let blob = object.to_string();
+                let mut request = http::Request::builder()
+                    .method("POST")
+                    .uri(inbox.as_str())
+                    .header(
+                        "Content-Type",
+                        "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
+                    )
+                    .body(blob.as_bytes().to_vec())
+                    .map_err(|e| ServerError::HandlerError(e.into()))?;
 
                 let owner = match context
                     .entity_store
@@ -192,8 +286,6 @@ pub async fn deliver_one(
                     None => return Ok(()),
                 };
 
-                let blob = object.to_string();
-
                 if let [Pointer::Id(key_id)] = &owner.main()[sec!(publicKey)] as &[_] {
                     if let Some(key) = context
                         .entity_store
@@ -206,15 +298,13 @@ pub async fn deliver_one(
                     }
                 }
 
-                let response = request
-                    .body_string(blob)
-                    .set_header(
-                        "Content-Type",
-                        "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
-                    )
+                let response = state
+                    .outbound
+                    .client
+                    .send(request, MAX_DELIVERY_RESPONSE)
                     .timeout(Duration::from_secs(7))
                     .await
-                    .map_err(ServerError::HttpError)?;
+                    .map_err(ServerError::StoreError)?;
 
                 println!(
                     " + deliver {} to {}: {}",
@@ -222,6 +312,27 @@ pub async fn deliver_one(
                     inbox,
                     response.status()
                 );
+
+                // Retry later if the remote server is having trouble.
+                let status = response.status();
+                if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                    return Err(ServerError::HandlerError(
+                        format!("delivery to {} failed with {}", inbox, status).into(),
+                    ));
+                }
+            }
+
+            Ok(())
+        }
+
+        // The object was marked as fresh when this was queued, so a failed refresh is
+        //  only tried again once it is stale again.
+        "refresh" => {
+            let state = state::of(context)?;
+            if let Err(e) =
+                refetch(context.entity_store, &state, budget, item.data.to_owned()).await
+            {
+                println!(" - failed to refresh {}: {}", item.data, e);
             }
 
             Ok(())
@@ -247,7 +358,11 @@ pub async fn register_delivery(
 
 use std::panic::AssertUnwindSafe;
 
-pub async fn loop_deliver(context: &mut Context<'_, '_>) -> Result<(), ServerError> {
+/// Handles the queue forever. Every item gets a fresh fetch budget.
+pub async fn loop_deliver(
+    context: &mut Context<'_, '_>,
+    budget: &FetchBudget,
+) -> Result<(), ServerError> {
     println!("+ Delivery thread start");
     loop {
         let item = context
@@ -257,7 +372,9 @@ pub async fn loop_deliver(context: &mut Context<'_, '_>) -> Result<(), ServerErr
             .map_err(ServerError::StoreError)?;
         match item {
             Some(val) => {
-                let delivery = AssertUnwindSafe(deliver_one(context, &val))
+                budget.reset();
+
+                let delivery = AssertUnwindSafe(deliver_one(context, &val, budget))
                     .catch_unwind()
                     .await;
 
@@ -0,0 +1,269 @@
+//! The federation policy of this server, which decides how other servers are treated.
+//!
+//! Every service reads its policy from its configuration on startup, and it can be changed
+//! at runtime by admins through the routes in this module. Changes made at runtime are not
+//! saved.
+
+use http::Uri;
+use http_service::{Body, Request, Response};
+use kroeg_tap::{as2, Context, StoreItem, User};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::config::{DomainPolicy, FederationConfig, ServerConfig};
+use crate::{router::RequestHandler, router::Route, scope, state, ServerError};
+
+/// Returns the host of an ID.
+pub fn host_of(id: &str) -> Option<String> {
+    id.parse::<Uri>()
+        .ok()
+        .and_then(|f| f.host().map(str::to_lowercase))
+}
+
+/// Returns the authority of an ID, i.e. its host and port.
+pub fn authority_of(id: &str) -> Option<String> {
+    id.parse::<Uri>()
+        .ok()
+        .and_then(|f| f.authority_part().map(|f| f.as_str().to_lowercase()))
+}
+
+/// The federation policy of a service.
+pub struct FederationPolicy {
+    local_host: Option<String>,
+    config: RwLock<FederationConfig>,
+}
+
+impl FederationPolicy {
+    /// Sets up the federation policy of a service from its configuration.
+    pub fn new(config: &ServerConfig) -> Self {
+        FederationPolicy {
+            local_host: host_of(&config.domain),
+            config: RwLock::new(config.federation.clone()),
+        }
+    }
+
+    /// Returns the current policy.
+    pub fn get(&self) -> FederationConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Changes the current policy.
+    pub fn update(&self, change: impl FnOnce(&mut FederationConfig)) {
+        change(&mut self.config.write().unwrap());
+    }
+
+    fn is_local(&self, host: &str) -> bool {
+        self.local_host.as_ref().map(|f| f == host) == Some(true)
+    }
+
+    /// Returns the policy for a remote domain, if any.
+    pub fn domain_policy(&self, host: &str) -> Option<DomainPolicy> {
+        if self.is_local(host) {
+            return None;
+        }
+
+        self.config.read().unwrap().domain_policy(host)
+    }
+
+    /// Checks if nothing should be exchanged with a domain at all.
+    pub fn is_rejected(&self, host: &str) -> bool {
+        !self.is_local(host) && self.config.read().unwrap().is_rejected(host)
+    }
+
+    /// Checks if nothing should be exchanged with the server an ID lives on.
+    pub fn is_rejected_id(&self, id: &str) -> bool {
+        match host_of(id) {
+            Some(host) => self.is_rejected(&host),
+            None => false,
+        }
+    }
+}
+
+/// Drops the media of an object, for domains with the `reject_media` policy.
+pub fn strip_media(item: &mut StoreItem) {
+    for prop in &[as2!(attachment), as2!(icon), as2!(image)] {
+        item.main_mut()[*prop].clear();
+    }
+}
+
+/// Checks if the user has been authenticated by an HTTP signature.
+pub fn is_signed(user: &User) -> bool {
+    user.token_identifier == "http-signature"
+}
+
+/// Returns the host of the server that signed the request, if it is signed.
+pub fn requester_host(user: &User) -> Option<String> {
+    if !is_signed(user) {
+        return None;
+    }
+
+    host_of(&user.subject)
+}
+
+/// A change to the policy of a single domain. A policy of `null` removes it.
+#[derive(Deserialize)]
+struct DomainChange {
+    domain: String,
+    policy: Option<DomainPolicy>,
+}
+
+/// A change to the whole policy. Anything that is left out stays as it is, and domains
+/// are changed one by one, like with `DomainChange`.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PolicyChange {
+    authorized_fetch: Option<bool>,
+    allowlist_only: Option<bool>,
+    allowed_domains: Option<Vec<String>>,
+
+    /// Domains to reject, on top of the ones that are already rejected.
+    blocked_domains: Vec<String>,
+    domains: HashMap<String, Option<DomainPolicy>>,
+}
+
+fn set_domain(policy: &mut FederationConfig, domain: &str, new: Option<DomainPolicy>) {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    policy
+        .blocked_domains
+        .retain(|f| f.to_lowercase() != domain);
+    match new {
+        Some(new) => policy.domains.insert(domain, new),
+        None => policy.domains.remove(&domain),
+    };
+}
+
+struct AdminHandler {
+    admins: Vec<String>,
+}
+
+impl AdminHandler {
+    fn check(&self, context: &Context<'_, '_>) -> Result<Option<Response>, ServerError> {
+        if self.admins.iter().any(|f| *f == context.user.subject) {
+            Ok(None)
+        } else {
+            Ok(Some(
+                http::Response::builder()
+                    .status(403)
+                    .body(Body::from("only admins can change the federation policy"))
+                    .unwrap(),
+            ))
+        }
+    }
+}
+
+fn policy_response(context: &Context<'_, '_>) -> Result<Response, ServerError> {
+    let policy = state::of(context)?.federation.get();
+
+    Ok(http::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&policy).unwrap()))
+        .unwrap())
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(request: Request) -> Result<T, ServerError> {
+    let body = request
+        .into_body()
+        .into_vec()
+        .await
+        .map_err(|f| ServerError::HandlerError(f.into()))?;
+
+    serde_json::from_slice(&body).map_err(ServerError::SerdeError)
+}
+
+/// Shows the current policy.
+struct GetPolicyHandler(AdminHandler);
+
+#[async_trait::async_trait]
+impl RequestHandler for GetPolicyHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        _: Request,
+    ) -> Result<Response, ServerError> {
+        if let Some(response) = self.0.check(context)? {
+            return Ok(response);
+        }
+
+        policy_response(context)
+    }
+}
+
+/// Changes several parts of the policy at once.
+struct SetPolicyHandler(AdminHandler);
+
+#[async_trait::async_trait]
+impl RequestHandler for SetPolicyHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: Request,
+    ) -> Result<Response, ServerError> {
+        if let Some(response) = self.0.check(context)? {
+            return Ok(response);
+        }
+
+        let change: PolicyChange = read_json(request).await?;
+        state::of(context)?.federation.update(|policy| {
+            if let Some(authorized_fetch) = change.authorized_fetch {
+                policy.authorized_fetch = authorized_fetch;
+            }
+
+            if let Some(allowlist_only) = change.allowlist_only {
+                policy.allowlist_only = allowlist_only;
+            }
+
+            if let Some(allowed_domains) = change.allowed_domains {
+                policy.allowed_domains = allowed_domains;
+            }
+
+            for domain in &change.blocked_domains {
+                set_domain(policy, domain, Some(DomainPolicy::Reject));
+            }
+
+            for (domain, new) in &change.domains {
+                set_domain(policy, domain, *new);
+            }
+        });
+
+        policy_response(context)
+    }
+}
+
+/// Sets or removes the policy for a single domain.
+struct SetDomainHandler(AdminHandler);
+
+#[async_trait::async_trait]
+impl RequestHandler for SetDomainHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        request: Request,
+    ) -> Result<Response, ServerError> {
+        if let Some(response) = self.0.check(context)? {
+            return Ok(response);
+        }
+
+        let change: DomainChange = read_json(request).await?;
+        state::of(context)?
+            .federation
+            .update(|policy| set_domain(policy, &change.domain, change.policy));
+
+        policy_response(context)
+    }
+}
+
+pub fn routes(config: &ServerConfig) -> Vec<Route> {
+    let admin = || AdminHandler {
+        admins: config.admins.clone(),
+    };
+
+    vec![
+        Route::get("/-/admin/federation", GetPolicyHandler(admin())).with_scope(scope::ADMIN),
+        Route::post("/-/admin/federation", SetPolicyHandler(admin())).with_scope(scope::ADMIN),
+        Route::post("/-/admin/federation/domain", SetDomainHandler(admin()))
+            .with_scope(scope::ADMIN),
+    ]
+}
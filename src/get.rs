@@ -2,11 +2,16 @@
 
 use http_service::{Body, Request, Response};
 use jsonld::nodemap::Pointer;
-use kroeg_tap::{as2, assemble, Authorizer, Context, DefaultAuthorizer, StoreError, StoreItem};
-use serde_json::json;
+use kroeg_tap::{
+    as2, assemble, kroeg, ldp, sec, Authorizer, Context, DefaultAuthorizer, StoreError, StoreItem,
+};
+use serde_json::{json, Value as JValue};
 use std::collections::HashSet;
 use url::Url;
 
+use crate::federation;
+use crate::scope;
+use crate::state;
 use crate::ServerError;
 
 async fn build_collection_page(
@@ -65,10 +70,19 @@ fn not_found() -> Response {
         .unwrap()
 }
 
+/// Returns the scope a bearer token needs to read this item.
+fn required_scope(item: &mut StoreItem) -> &'static str {
+    match &item.meta()[kroeg!(box)] as &[Pointer] {
+        [Pointer::Id(id)] if id == ldp!(inbox) => scope::READ_INBOX,
+        [Pointer::Id(id)] if id == as2!(outbox) => scope::READ_OUTBOX,
+        _ => scope::READ,
+    }
+}
+
 pub async fn get_raw(
     context: &mut Context<'_, '_>,
     url: &str,
-) -> Result<Option<StoreItem>, StoreError> {
+) -> Result<Option<StoreItem>, ServerError> {
     let parsed = match Url::parse(url) {
         Ok(url) => url,
         Err(_) => return Ok(None),
@@ -76,7 +90,12 @@ pub async fn get_raw(
 
     let id = parsed[..url::Position::BeforeQuery].trim_end_matches('?');
 
-    let mut item = match context.entity_store.get(id.to_owned(), false).await? {
+    let mut item = match context
+        .entity_store
+        .get(id.to_owned(), false)
+        .await
+        .map_err(ServerError::StoreError)?
+    {
         Some(item)
             if DefaultAuthorizer
                 .can_show(context, &item)
@@ -88,6 +107,8 @@ pub async fn get_raw(
         _ => return Ok(None),
     };
 
+    scope::require_scope(&context.user, required_scope(&mut item))?;
+
     if item.is_owned(context)
         && item
             .main()
@@ -96,7 +117,9 @@ pub async fn get_raw(
             .any(|f| f == as2!(OrderedCollection))
     {
         if let Some(query) = parsed.query() {
-            item = build_collection_page(context, item, query.to_owned()).await?;
+            item = build_collection_page(context, item, query.to_owned())
+                .await
+                .map_err(ServerError::StoreError)?;
         } else {
             let id = format!("{}?first", item.id());
             item.main_mut()[as2!(first)].push(Pointer::Id(id));
@@ -106,6 +129,74 @@ pub async fn get_raw(
     Ok(Some(item))
 }
 
+fn pointers_to_json(pointers: &[Pointer]) -> JValue {
+    JValue::Array(
+        pointers
+            .iter()
+            .filter_map(|f| match f {
+                Pointer::Id(id) => Some(json!({ "@id": id })),
+                Pointer::Value(val) => Some(match &val.type_id {
+                    Some(type_id) => json!({ "@value": val.value, "@type": type_id }),
+                    None => json!({ "@value": val.value }),
+                }),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+fn minimal_key(key: &StoreItem) -> JValue {
+    json!({
+        "@id": key.id(),
+        "@type": key.main().types,
+        sec!(owner): pointers_to_json(&key.main()[sec!(owner)]),
+        sec!(publicKeyPem): pointers_to_json(&key.main()[sec!(publicKeyPem)])
+    })
+}
+
+/// Builds the minimal representation of an item that is shown to unsigned requests when
+/// authorized fetch is enabled: only keys, and actors with just their keys and inbox.
+async fn minimal_representation(
+    context: &mut Context<'_, '_>,
+    item: &StoreItem,
+) -> Result<Option<JValue>, ServerError> {
+    if item.main().types.iter().any(|f| f == sec!(Key)) {
+        return Ok(Some(minimal_key(item)));
+    }
+
+    let mut keys = Vec::new();
+    for key in &item.main()[sec!(publicKey)] {
+        if let Pointer::Id(key) = key {
+            if let Some(key) = context
+                .entity_store
+                .get(key.to_owned(), true)
+                .await
+                .map_err(ServerError::StoreError)?
+            {
+                keys.push(minimal_key(&key));
+            }
+        }
+    }
+
+    if keys.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(json!({
+        "@id": item.id(),
+        "@type": item.main().types,
+        sec!(publicKey): keys,
+        ldp!(inbox): pointers_to_json(&item.main()[ldp!(inbox)])
+    })))
+}
+
+fn refused(status: u16, message: &str) -> Response {
+    http::Response::builder()
+        .status(status)
+        .body(Body::from(message.to_owned()))
+        .unwrap()
+}
+
 pub struct GetHandler;
 
 #[async_trait::async_trait]
@@ -116,18 +207,29 @@ impl crate::router::RequestHandler for GetHandler {
         request: Request,
     ) -> Result<Response, ServerError> {
         let id = format!("{}{}", context.server_base, request.uri());
+        let state = state::of(context)?;
+        if let Some(host) = federation::requester_host(&context.user) {
+            if state.federation.is_rejected(&host) {
+                return Ok(refused(403, "requests from this domain are refused"));
+            }
+        }
 
-        let item = match get_raw(context, &id)
-            .await
-            .map_err(ServerError::StoreError)?
-        {
+        let item = match get_raw(context, &id).await? {
             Some(item) => item,
             None => return Ok(not_found()),
         };
 
-        let assembled = assemble(&item, 0, context, &DefaultAuthorizer, &mut HashSet::new())
-            .await
-            .map_err(ServerError::StoreError)?;
+        let authorized_fetch = state.federation.get().authorized_fetch;
+        let assembled = if authorized_fetch && context.user.subject == "anonymous" {
+            match minimal_representation(context, &item).await? {
+                Some(minimal) => minimal,
+                None => return Ok(refused(401, "this server requires signed requests")),
+            }
+        } else {
+            assemble(&item, 0, context, &DefaultAuthorizer, &mut HashSet::new())
+                .await
+                .map_err(ServerError::StoreError)?
+        };
 
         let compacted = crate::context::compact(&context.server_base, &assembled)
             .await
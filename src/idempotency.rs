@@ -0,0 +1,91 @@
+//! Idempotency keys for outbox POSTs.
+//!
+//! Clients on bad connections retry a POST when they don't see the response, and every
+//! retry would create a new activity. With an `Idempotency-Key` header, the response to
+//! the first POST is replayed for repeats of the same key by the same user instead.
+
+use chashmap::CHashMap;
+use std::time::{Duration, Instant};
+
+enum Entry {
+    /// The first request with this key is still being handled.
+    Pending,
+    Done {
+        status: u16,
+        location: String,
+    },
+}
+
+lazy_static::lazy_static! {
+    /// The responses by user and key, with when they were first seen.
+    static ref RESPONSES: CHashMap<String, (Instant, Entry)> = CHashMap::new();
+}
+
+pub enum Reserved {
+    /// The key hasn't been used yet, so the request should be handled.
+    New(Reservation),
+    /// Another request with the key is being handled right now.
+    InProgress,
+    /// The key has been used, and this was the response.
+    Done { status: u16, location: String },
+}
+
+/// A key that is being used by a request. Unless the response is stored with `complete`,
+/// the key is released when this is dropped, so a failed request can be retried.
+pub struct Reservation(Option<String>);
+
+impl Reservation {
+    pub fn complete(mut self, status: u16, location: &str) {
+        if let Some(key) = self.0.take() {
+            RESPONSES.insert(
+                key,
+                (
+                    Instant::now(),
+                    Entry::Done {
+                        status,
+                        location: location.to_owned(),
+                    },
+                ),
+            );
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take() {
+            RESPONSES.remove(&key);
+        }
+    }
+}
+
+/// Looks up an idempotency key of a user, reserving it if it is new or has expired.
+pub fn reserve(user: &str, key: &str, window: Duration) -> Reserved {
+    if RESPONSES.len() > 10_000 {
+        RESPONSES.retain(|_, entry| entry.0.elapsed() < window);
+    }
+
+    let key = format!("{} {}", user, key);
+    let mut existing = None;
+
+    RESPONSES.upsert(
+        key.to_owned(),
+        || (Instant::now(), Entry::Pending),
+        |entry| {
+            if entry.0.elapsed() >= window {
+                *entry = (Instant::now(), Entry::Pending);
+                return;
+            }
+
+            existing = Some(match &entry.1 {
+                Entry::Pending => Reserved::InProgress,
+                Entry::Done { status, location } => Reserved::Done {
+                    status: *status,
+                    location: location.to_owned(),
+                },
+            });
+        },
+    );
+
+    existing.unwrap_or_else(|| Reserved::New(Reservation(Some(key))))
+}
@@ -0,0 +1,185 @@
+use jsonld::nodemap::Pointer;
+use kroeg_tap::{
+    as2, kroeg, sec, Context, MessageHandler, QuadQuery, QueryId, QueryObject, StoreError,
+    StoreItem,
+};
+use serde_json::json;
+use std::collections::HashSet;
+use std::error::Error;
+
+use super::{is_owned_by, remove_from_collections};
+use crate::post::pointer_ids;
+
+/// Handles Delete activities from other servers. Deleted objects are replaced with a
+/// Tombstone and removed from all collections, and an actor deleting themselves has
+/// everything they made deleted with them.
+pub struct ServerDeleteHandler;
+
+/// Replaces an object with a Tombstone, and removes it from every collection.
+async fn delete_object(
+    context: &mut Context<'_, '_>,
+    mut item: StoreItem,
+) -> Result<(), StoreError> {
+    let id = item.id().to_owned();
+    let former_types: Vec<_> = item
+        .main()
+        .types
+        .iter()
+        .filter(|f| *f != as2!(Tombstone))
+        .map(|f| json!({ "@id": f }))
+        .collect();
+
+    let mut tombstone = StoreItem::parse(
+        &id,
+        &json!({
+            "@id": id,
+            "@type": [as2!(Tombstone)],
+            as2!(formerType): former_types
+        }),
+    )?;
+
+    // Whoever owned the object still owns its Tombstone, e.g. to delete it again.
+    for property in &[as2!(attributedTo), as2!(actor)] {
+        tombstone.main_mut()[*property] = item.main()[*property].clone();
+    }
+
+    // Local objects stay local, so their Tombstone is served instead of retrieved, and
+    //  remote ones are refreshed like the object they replace.
+    tombstone.meta()[kroeg!(instance)] = item.meta()[kroeg!(instance)].clone();
+    tombstone.meta()[kroeg!(fetched)] = item.meta()[kroeg!(fetched)].clone();
+
+    context
+        .entity_store
+        .put(id.to_owned(), &mut tombstone)
+        .await?;
+    remove_from_collections(context, &id).await
+}
+
+/// Finds the objects with a property pointing to `id`.
+async fn find_referring(
+    context: &mut Context<'_, '_>,
+    property: &str,
+    id: &str,
+) -> Result<Vec<String>, StoreError> {
+    let query = context
+        .entity_store
+        .query(vec![QuadQuery(
+            QueryId::Placeholder(0),
+            QueryId::Value(property.to_owned()),
+            QueryObject::Id(QueryId::Value(id.to_owned())),
+        )])
+        .await?;
+
+    Ok(query.into_iter().filter_map(|mut f| f.pop()).collect())
+}
+
+/// Deletes everything an actor made, then the actor and their keys.
+async fn purge_actor(
+    context: &mut Context<'_, '_>,
+    actor: &str,
+    activity: &str,
+) -> Result<(), StoreError> {
+    let mut made = HashSet::new();
+    made.extend(find_referring(context, as2!(attributedTo), actor).await?);
+    made.extend(find_referring(context, as2!(actor), actor).await?);
+
+    // The Delete itself is kept, as it is what tells others what happened.
+    made.remove(activity);
+
+    for id in made {
+        if let Some(item) = context.entity_store.get(id, true).await? {
+            delete_object(context, item).await?;
+        }
+    }
+
+    let item = match context.entity_store.get(actor.to_owned(), true).await? {
+        Some(item) => item,
+        None => return Ok(()),
+    };
+
+    // Without their keys, nothing can be signed as the actor anymore.
+    for key in pointer_ids(&item.main()[sec!(publicKey)]) {
+        if let Some(key) = context.entity_store.get(key, true).await? {
+            delete_object(context, key).await?;
+        }
+    }
+
+    delete_object(context, item).await
+}
+
+#[async_trait::async_trait]
+impl MessageHandler for ServerDeleteHandler {
+    async fn handle(
+        &self,
+        context: &mut Context<'_, '_>,
+        _inbox: &mut String,
+        elem: &mut String,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let activity = match context.entity_store.get(elem.to_owned(), true).await? {
+            Some(activity) => activity,
+            None => return Ok(()),
+        };
+
+        if !activity.main().types.iter().any(|f| f == as2!(Delete)) {
+            return Ok(());
+        }
+
+        let actor = match &activity.main()[as2!(actor)] as &[Pointer] {
+            [Pointer::Id(actor)] => actor.to_owned(),
+            _ => return Err("a Delete needs exactly one actor".into()),
+        };
+
+        // Check all objects before deleting any, so a Delete is applied fully or not at all.
+        let mut objects = Vec::new();
+        for object in pointer_ids(&activity.main()[as2!(object)]) {
+            if object == actor {
+                objects.push((object, None));
+                continue;
+            }
+
+            let item = match context.entity_store.get(object.to_owned(), true).await? {
+                Some(item) => item,
+                None => continue,
+            };
+
+            // Already deleted, e.g. by the same Delete on another inbox.
+            if item.main().types.iter().any(|f| f == as2!(Tombstone)) {
+                continue;
+            }
+
+            if !is_owned_by(&item, &actor) {
+                return Err(format!("{} can't delete {}", actor, object).into());
+            }
+
+            objects.push((object, Some(item)));
+        }
+
+        for (object, item) in objects {
+            let item = match item {
+                Some(item) => item,
+                None => {
+                    purge_actor(context, &actor, elem).await?;
+                    continue;
+                }
+            };
+
+            delete_object(context, item).await?;
+
+            // The activities of the actor about it, like its Create, go away from
+            //  inboxes and the like too.
+            for activity in find_referring(context, as2!(object), &object).await? {
+                if activity == *elem {
+                    continue;
+                }
+
+                if let Some(item) = context.entity_store.get(activity.to_owned(), true).await? {
+                    if is_owned_by(&item, &actor) {
+                        remove_from_collections(context, &activity).await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
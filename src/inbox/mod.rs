@@ -0,0 +1,53 @@
+//! Handlers for activities delivered to inboxes, next to the ones in `kroeg_tap_activitypub`.
+
+use kroeg_tap::{as2, Context, StoreError, StoreItem};
+
+use crate::post::pointer_ids;
+
+mod delete;
+mod update;
+pub use self::delete::*;
+pub use self::update::*;
+
+/// Checks if an actor owns an object, i.e. it is attributed to them or they are its actor.
+/// Objects with neither, like Tombstones, are only owned by the actor their ID is under,
+/// like `{actor}#key` or `{actor}/notes/1`.
+fn is_owned_by(item: &StoreItem, actor: &str) -> bool {
+    let id = item.id();
+    if id == actor {
+        return true;
+    }
+
+    let mut owners = pointer_ids(&item.main()[as2!(attributedTo)]);
+    owners.extend(pointer_ids(&item.main()[as2!(actor)]));
+
+    if !owners.is_empty() {
+        return owners.iter().any(|f| f == actor);
+    }
+
+    id.starts_with(actor)
+        && match id.as_bytes().get(actor.len()) {
+            Some(b'/') | Some(b'#') => true,
+            _ => false,
+        }
+}
+
+/// Removes an object from every collection it is in.
+async fn remove_from_collections(
+    context: &mut Context<'_, '_>,
+    id: &str,
+) -> Result<(), StoreError> {
+    let collections = context
+        .entity_store
+        .read_collection_inverse(id.to_owned())
+        .await?;
+
+    for collection in collections.items {
+        context
+            .entity_store
+            .remove_collection(collection, id.to_owned())
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use jsonld::nodemap::{Pointer, Value as NValue};
+use kroeg_tap::{as2, kroeg, Context, MessageHandler, StoreError, StoreItem};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use super::is_owned_by;
+use crate::post::pointer_ids;
+use crate::state;
+use crate::store::{refetch, FetchBudget};
+
+/// Handles Update activities from other servers. The new versions of the objects are only
+/// stored if the actor owns them, and the versions they replace are kept as revisions.
+///
+/// The objects that came with the activity are held back from `store_all` and given to
+/// this handler, so it is made for each delivery. Objects that didn't come with it are
+/// retrieved from their origin.
+pub struct ServerUpdateHandler(Mutex<HashMap<String, StoreItem>>, FetchBudget);
+
+impl ServerUpdateHandler {
+    pub fn new(objects: HashMap<String, StoreItem>, budget: FetchBudget) -> Self {
+        ServerUpdateHandler(Mutex::new(objects), budget)
+    }
+}
+
+fn digest(id: &str) -> String {
+    let digest = base64::encode_config(&Sha256::digest(id.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+    digest[..16].to_owned()
+}
+
+/// Returns the local ID of the collection with the earlier versions of an object. The
+/// object links to it with `kroeg:revisions`.
+pub fn revisions_id(base: &str, id: &str) -> String {
+    format!("{}/-/revisions/{}", base, digest(id))
+}
+
+/// The ID the version of an object replaced by an Update is kept at.
+fn revision_id(base: &str, id: &str, update: &str) -> String {
+    format!("{}/{}", revisions_id(base, id), digest(update))
+}
+
+/// The collection of the Updates applied to an object, so each is only applied once.
+fn applied_id(base: &str, id: &str) -> String {
+    format!("{}#applied", revisions_id(base, id))
+}
+
+/// Marks an item as owned by this server, so it is served from here.
+fn own(context: &Context<'_, '_>, item: &mut StoreItem) {
+    item.meta()[kroeg!(instance)] = vec![Pointer::Value(NValue {
+        value: json!(context.instance_id),
+        type_id: Some("http://www.w3.org/2001/XMLSchema#integer".to_owned()),
+        language: None,
+    })];
+}
+
+async fn is_applied(
+    context: &mut Context<'_, '_>,
+    object: &str,
+    update: &str,
+) -> Result<bool, StoreError> {
+    let found = context
+        .entity_store
+        .find_collection(applied_id(&context.server_base, object), update.to_owned())
+        .await?;
+
+    Ok(found.items.iter().any(|f| f == update))
+}
+
+/// Points the nodes of a stored object with ID `from` at `to` instead.
+fn rename(json: &mut Value, from: &str, to: &str) {
+    match json {
+        Value::Array(nodes) => {
+            for node in nodes {
+                rename(node, from, to);
+            }
+        }
+
+        Value::Object(node) => {
+            if node.get("@id").and_then(|f| f.as_str()) == Some(from) {
+                node.insert("@id".to_owned(), Value::String(to.to_owned()));
+            }
+        }
+
+        _ => {}
+    }
+}
+
+/// Stores a copy of an object as a revision of it, and links the object to its revisions.
+async fn keep_revision(
+    context: &mut Context<'_, '_>,
+    item: &StoreItem,
+    update: &str,
+) -> Result<(), StoreError> {
+    let revisions = revisions_id(&context.server_base, item.id());
+    let revision = revision_id(&context.server_base, item.id(), update);
+
+    let mut json = item.to_json();
+    rename(&mut json, item.id(), &revision);
+
+    let mut copy = StoreItem::parse(&revision, &json)?;
+    own(context, &mut copy);
+    context
+        .entity_store
+        .put(revision.to_owned(), &mut copy)
+        .await?;
+
+    if context
+        .entity_store
+        .get(revisions.to_owned(), true)
+        .await?
+        .is_none()
+    {
+        let mut collection = StoreItem::parse(
+            &revisions,
+            &json!({
+                "@id": revisions,
+                "@type": [as2!(OrderedCollection)],
+                as2!(partOf): [{ "@id": item.id() }]
+            }),
+        )?;
+        own(context, &mut collection);
+        context
+            .entity_store
+            .put(revisions.to_owned(), &mut collection)
+            .await?;
+    }
+
+    context
+        .entity_store
+        .insert_collection(revisions, revision)
+        .await
+}
+
+/// Links the stored version of an object to the collection of its revisions.
+async fn link_revisions(context: &mut Context<'_, '_>, object: &str) -> Result<(), StoreError> {
+    let revisions = revisions_id(&context.server_base, object);
+    let mut item = match context.entity_store.get(object.to_owned(), true).await? {
+        Some(item) => item,
+        None => return Ok(()),
+    };
+
+    item.main_mut()[kroeg!(revisions)] = vec![Pointer::Id(revisions)];
+    context.entity_store.put(object.to_owned(), &mut item).await
+}
+
+#[async_trait::async_trait]
+impl MessageHandler for ServerUpdateHandler {
+    async fn handle(
+        &self,
+        context: &mut Context<'_, '_>,
+        _inbox: &mut String,
+        elem: &mut String,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let activity = match context.entity_store.get(elem.to_owned(), true).await? {
+            Some(activity) => activity,
+            None => return Ok(()),
+        };
+
+        if !activity.main().types.iter().any(|f| f == as2!(Update)) {
+            return Ok(());
+        }
+
+        let actor = match &activity.main()[as2!(actor)] as &[Pointer] {
+            [Pointer::Id(actor)] => actor.to_owned(),
+            _ => return Err("an Update needs exactly one actor".into()),
+        };
+
+        for object in pointer_ids(&activity.main()[as2!(object)]) {
+            let update = self.0.lock().unwrap().remove(&object);
+
+            // Already applied, e.g. for another recipient on the shared inbox.
+            if is_applied(context, &object, elem).await? {
+                continue;
+            }
+
+            let current = context.entity_store.get(object.to_owned(), true).await?;
+            if let Some(current) = &current {
+                if current.main().types.iter().any(|f| f == as2!(Tombstone)) {
+                    return Err(format!("{} was deleted", object).into());
+                }
+            }
+
+            let owned = current
+                .iter()
+                .chain(update.iter())
+                .all(|f| is_owned_by(f, &actor));
+            if !owned {
+                return Err(format!("{} can't update {}", actor, object).into());
+            }
+
+            if let Some(current) = &current {
+                keep_revision(context, current, elem).await?;
+            }
+
+            match update {
+                Some(mut update) => {
+                    context
+                        .entity_store
+                        .put(object.to_owned(), &mut update)
+                        .await?
+                }
+                None => {
+                    let state = state::of(context)?;
+                    refetch(context.entity_store, &state, &self.1, object.to_owned()).await?;
+                }
+            }
+
+            if current.is_some() {
+                link_revisions(context, &object).await?;
+            }
+
+            context
+                .entity_store
+                .insert_collection(applied_id(&context.server_base, &object), elem.to_owned())
+                .await?;
+        }
+
+        Ok(())
+    }
+}
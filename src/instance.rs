@@ -0,0 +1,208 @@
+//! The instance actor, which signs the requests the server makes on its own behalf.
+//!
+//! Servers running in "secure mode" only respond to signed GET requests, so all remote
+//! objects are retrieved with a signature by this actor. Every service has its own actor
+//! and key, which are loaded or created once, by `KroegService::new`.
+
+use http::Uri;
+use http_service::{Body, Request, Response};
+use jsonld::nodemap::{Pointer, Value};
+use kroeg_tap::{as2, kroeg, sec, Context, EntityStore, StoreError, StoreItem};
+use openssl::{
+    pkey::{PKey, Private},
+    rsa::Rsa,
+};
+use serde_json::{json, Value as JValue};
+
+use crate::state;
+use crate::{router::RequestHandler, router::Route, ServerError};
+
+/// The path the instance actor is served at.
+pub const ACTOR_PATH: &str = "/-/actor";
+
+#[derive(Clone)]
+pub struct InstanceKey {
+    pub key_id: String,
+    pub public_key_pem: String,
+    pub private_key: PKey<Private>,
+}
+
+pub fn actor_id(base: &str) -> String {
+    format!("{}{}", base, ACTOR_PATH)
+}
+
+pub fn key_id(base: &str) -> String {
+    format!("{}{}#main-key", base, ACTOR_PATH)
+}
+
+fn string_value(item: &[Pointer]) -> Option<String> {
+    match item {
+        [Pointer::Value(Value {
+            value: JValue::String(val),
+            ..
+        })] => Some(val.to_owned()),
+        _ => None,
+    }
+}
+
+/// Creates the instance actor and its key, and stores them.
+async fn create(
+    store: &mut dyn EntityStore,
+    base: &str,
+    instance_id: u32,
+) -> Result<(), StoreError> {
+    let actor_id = actor_id(base);
+    let key_id = key_id(base);
+    let host = base
+        .parse::<Uri>()
+        .ok()
+        .and_then(|f| f.host().map(str::to_owned))
+        .unwrap_or_else(|| base.to_owned());
+
+    let rsa = Rsa::generate(2048)?;
+    let public_key_pem = String::from_utf8(rsa.public_key_to_pem()?)?;
+    let private_key_pem = String::from_utf8(rsa.private_key_to_pem()?)?;
+
+    let mut actor = StoreItem::parse(
+        &actor_id,
+        &json!({
+            "@id": actor_id,
+            "@type": [as2!(Application)],
+            as2!(preferredUsername): [{ "@value": host }],
+            sec!(publicKey): [{ "@id": key_id }]
+        }),
+    )?;
+
+    let mut key = StoreItem::parse(
+        &key_id,
+        &json!({
+            "@id": key_id,
+            "@type": [sec!(Key)],
+            sec!(owner): [{ "@id": actor_id }],
+            sec!(publicKeyPem): [{ "@value": public_key_pem }]
+        }),
+    )?;
+
+    for item in &mut [&mut actor, &mut key] {
+        item.meta()[kroeg!(instance)] = vec![Pointer::Value(Value {
+            value: json!(instance_id),
+            type_id: Some("http://www.w3.org/2001/XMLSchema#integer".to_owned()),
+            language: None,
+        })];
+    }
+
+    key.meta()[sec!(privateKeyPem)] = vec![Pointer::Value(Value {
+        value: JValue::String(private_key_pem),
+        type_id: None,
+        language: None,
+    })];
+
+    store.put(actor_id, &mut actor).await?;
+    store.put(key_id, &mut key).await
+}
+
+/// Loads the key of the instance actor, creating the actor if it doesn't exist yet.
+pub async fn load(
+    store: &mut dyn EntityStore,
+    base: &str,
+    instance_id: u32,
+) -> Result<InstanceKey, StoreError> {
+    let key_id = key_id(base);
+    let mut key = match store.get(key_id.to_owned(), true).await? {
+        Some(key) => key,
+        None => {
+            create(store, base, instance_id).await?;
+
+            // Read it back, in case another process created it at the same time.
+            match store.get(key_id.to_owned(), true).await? {
+                Some(key) => key,
+                None => return Err("instance key went missing".into()),
+            }
+        }
+    };
+
+    let public_key_pem = string_value(&key.main()[sec!(publicKeyPem)]);
+    let private_key_pem = string_value(&key.meta()[sec!(privateKeyPem)]);
+
+    let (public_key_pem, private_key_pem) = match (public_key_pem, private_key_pem) {
+        (Some(public), Some(private)) => (public, private),
+        _ => return Err("instance key is missing its key data".into()),
+    };
+
+    let private_key = PKey::from_rsa(Rsa::private_key_from_pem(private_key_pem.as_bytes())?)?;
+
+    Ok(InstanceKey {
+        key_id,
+        public_key_pem,
+        private_key,
+    })
+}
+
+struct InstanceActorHandler;
+
+#[async_trait::async_trait]
+impl RequestHandler for InstanceActorHandler {
+    async fn run(
+        &self,
+        context: &mut Context<'_, '_>,
+        _: Request,
+    ) -> Result<Response, ServerError> {
+        let key = match state::of(context)?.outbound.key.clone() {
+            Some(key) => key,
+            None => {
+                return Ok(http::Response::builder()
+                    .status(404)
+                    .body(Body::from("not found"))
+                    .unwrap())
+            }
+        };
+
+        let actor_id = actor_id(&context.server_base);
+        let host = context
+            .server_base
+            .parse::<Uri>()
+            .ok()
+            .and_then(|f| f.host().map(str::to_owned))
+            .unwrap_or_default();
+
+        let response = json!({
+            "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+            "id": actor_id,
+            "type": "Application",
+            "preferredUsername": host,
+            "inbox": format!("{}/inbox", actor_id),
+            "publicKey": {
+                "id": key.key_id,
+                "owner": actor_id,
+                "publicKeyPem": key.public_key_pem
+            }
+        });
+
+        Ok(http::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/activity+json")
+            .body(Body::from(response.to_string()))
+            .unwrap())
+    }
+}
+
+/// The inbox of the instance actor. Nothing is sent to it on purpose, so anything that
+/// arrives is accepted and dropped.
+struct InstanceInboxHandler;
+
+#[async_trait::async_trait]
+impl RequestHandler for InstanceInboxHandler {
+    async fn run(&self, _: &mut Context<'_, '_>, _: Request) -> Result<Response, ServerError> {
+        Ok(http::Response::builder()
+            .status(202)
+            .body(Body::from("accepted"))
+            .unwrap())
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    vec![
+        Route::get(ACTOR_PATH, InstanceActorHandler),
+        Route::post(&format!("{}/inbox", ACTOR_PATH), InstanceInboxHandler),
+    ]
+}
@@ -1,13 +1,26 @@
 mod authentication;
+pub mod cache;
+pub mod client;
 pub mod config;
 pub mod context;
 pub mod delivery;
+pub mod federation;
 pub mod get;
+mod idempotency;
+pub mod inbox;
+pub mod instance;
 pub mod jwt;
+pub mod memory;
 pub mod nodeinfo;
 pub mod post;
+mod ratelimit;
 pub mod request;
 pub mod router;
+pub mod scope;
+pub mod signature;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
 pub mod store;
 pub mod webfinger;
 
@@ -21,6 +34,10 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use crate::cache::CachingEntityStore;
+use crate::client::HttpClient;
+use crate::request::Outbound;
+use crate::state::ServiceState;
 use crate::store::RetrievingEntityStore;
 
 #[derive(Debug)]
@@ -33,6 +50,8 @@ pub enum ServerError {
     HandlerError(Box<dyn Error + Send + Sync + 'static>),
     PostToNonbox,
     BadSharedInbox,
+    MissingScope(String),
+    StateError(state::StateError),
     Test,
 }
 
@@ -47,9 +66,14 @@ impl fmt::Display for ServerError {
             ServerError::HandlerError(err) => write!(f, "handler error: {}", err),
             ServerError::Test => write!(f, "Test!\n"),
             ServerError::PostToNonbox => write!(f, "tried to POST to a non-inbox/outbox entity"),
-            ServerError::BadSharedInbox => {
-                write!(f, "Mastodon quirk: actor != authorization token")
+            ServerError::BadSharedInbox => write!(
+                f,
+                "the actor of the activity is not the owner of the key that signed the request"
+            ),
+            ServerError::MissingScope(scope) => {
+                write!(f, "this token is missing the required scope: {}", scope)
             }
+            ServerError::StateError(err) => write!(f, "{}", err),
         }
     }
 }
@@ -62,11 +86,18 @@ impl Error for ServerError {
             ServerError::StoreError(err) => Some(err.as_ref()),
             ServerError::ExpansionError(err) => Some(err),
             ServerError::CompactionError(err) => Some(err),
+            ServerError::StateError(err) => Some(err),
             _ => None,
         }
     }
 }
 
+impl From<state::StateError> for ServerError {
+    fn from(err: state::StateError) -> Self {
+        ServerError::StateError(err)
+    }
+}
+
 /// A store connection pool.
 pub trait StorePool: Send + Sync + 'static {
     type LeasedConnection: LeasedConnection;
@@ -82,27 +113,67 @@ pub trait LeasedConnection: Send {
 
 /// The main service struct for Kroeg.
 /// For each handled request, an instance of this struct is created by the KroegServiceBuilder.
-/// This struct knows how to talk to the database, and has a list of routes.
+/// This struct knows how to talk to the database, has a list of routes, and holds the
+/// state shared by all requests, like the HTTP client and the object cache, if it is enabled.
 #[derive(Clone)]
-pub struct KroegService<T: StorePool>(Arc<(T, config::ServerConfig, Vec<router::Route>)>);
+pub struct KroegService<T: StorePool>(Arc<(T, Arc<ServiceState>, Vec<router::Route>)>);
 
 impl<T: StorePool> KroegService<T> {
-    pub fn new(
+    /// Creates a service, which makes all its outbound requests with `client`. This loads
+    /// the instance actor, creating it in the store if it doesn't exist yet.
+    pub async fn new(
         store_pool: T,
         config: config::ServerConfig,
         routes: Vec<router::Route>,
-    ) -> KroegService<T> {
-        KroegService(Arc::new((store_pool, config, routes)))
+        client: Arc<dyn HttpClient>,
+    ) -> Result<KroegService<T>, StoreError> {
+        // Our own context is never retrieved, so this works without a reachable domain.
+        context::preload(
+            &format!("{}/-/context", config.domain),
+            context::read_context(),
+        );
+
+        let key = {
+            let mut connection = store_pool.connect().await?;
+            let (entity_store, _) = connection.get();
+
+            instance::load(entity_store, &config.domain, config.instance_id).await?
+        };
+
+        let outbound = Outbound {
+            client,
+            policy: config.outbound.clone(),
+            key: Some(key),
+        };
+        let state = ServiceState::register(config, outbound)?;
+
+        Ok(KroegService(Arc::new((store_pool, state, routes))))
+    }
+
+    /// Returns the state shared by all requests to this service.
+    pub fn state(&self) -> Arc<ServiceState> {
+        (self.0).1.clone()
     }
 }
 
-/// Launches a delivery task.
-pub async fn launch_delivery<T: StorePool>(pool: T, config: config::ServerConfig) {
+/// Launches a delivery task for a service.
+pub async fn launch_delivery<T: StorePool>(service: KroegService<T>) {
+    let outbound = (service.0).1.outbound.clone();
+
+    context::with_outbound(outbound, run_delivery(service)).await
+}
+
+async fn run_delivery<T: StorePool>(service: KroegService<T>) {
+    let (pool, state) = (&(service.0).0, &(service.0).1);
+    let config = &state.config;
+
     loop {
         let mut pool = pool.connect().await.unwrap();
 
         let (entity_store, queue_store) = pool.get();
-        let mut entity_store = RetrievingEntityStore::new(entity_store, config.domain.to_owned());
+        let entity_store = CachingEntityStore::new(entity_store, state.cache.clone());
+        let mut entity_store = RetrievingEntityStore::new(entity_store, state.clone());
+        let budget = entity_store.budget();
 
         let mut context = Context {
             server_base: config.domain.to_owned(),
@@ -115,7 +186,7 @@ pub async fn launch_delivery<T: StorePool>(pool: T, config: config::ServerConfig
             queue_store,
         };
 
-        if let Err(e) = delivery::loop_deliver(&mut context).await {
+        if let Err(e) = delivery::loop_deliver(&mut context, &budget).await {
             println!(" - delivery thread failed: {:?}", e);
         }
     }
@@ -132,18 +203,36 @@ impl<T: StorePool> HttpService for KroegService<T> {
 
     fn respond(&self, _: &mut (), req: http_service::Request) -> Self::ResponseFuture {
         let ptr = self.0.clone();
+        let outbound = ptr.1.outbound.clone();
 
-        Box::pin(async move {
-            let (parts, body) = req.into_parts();
+        Box::pin(context::with_outbound(outbound, async move {
+            let (mut parts, body) = req.into_parts();
             let response = async move {
                 let mut database = ptr.0.connect().await.map_err(ServerError::StoreError)?;
 
                 let (entity_store, queue_store) = database.get();
 
-                let mut entity_store =
-                    RetrievingEntityStore::new(entity_store, ptr.1.domain.to_owned());
+                let entity_store = CachingEntityStore::new(entity_store, ptr.1.cache.clone());
+                let mut entity_store = RetrievingEntityStore::new(entity_store, ptr.1.clone());
+                let config = &ptr.1.config;
+
+                // Handlers that retrieve objects themselves take from the same budget.
+                parts.extensions.insert(entity_store.budget());
 
-                let user = match authentication::user_from_request(&parts, &mut entity_store).await
+                // Before authenticating, as verifying a signature may retrieve keys.
+                if let Some(retry_after) = ratelimit::check(&parts, &mut entity_store, &ptr.1)
+                    .await
+                    .map_err(ServerError::StoreError)?
+                {
+                    return Ok(ratelimit::too_many_requests(retry_after));
+                }
+
+                let user = match authentication::user_from_request(
+                    &parts,
+                    &mut entity_store,
+                    &ptr.1,
+                )
+                .await
                 {
                     Ok(user) => user,
                     Err(e) => {
@@ -159,23 +248,33 @@ impl<T: StorePool> HttpService for KroegService<T> {
                 println!(" - {} {} ({:?})", parts.method, parts.uri, user.subject);
 
                 let mut context = Context {
-                    server_base: ptr.1.domain.to_owned(),
-                    name: ptr.1.name.to_owned(),
-                    description: ptr.1.description.to_owned(),
-                    instance_id: ptr.1.instance_id,
+                    server_base: config.domain.to_owned(),
+                    name: config.name.to_owned(),
+                    description: config.description.to_owned(),
+                    instance_id: config.instance_id,
                     entity_store: &mut entity_store,
-                    queue_store,
+                    queue_store: &mut *queue_store,
                     user,
                 };
 
-                if let Some(route) = ptr.2.iter().rev().find(|f| f.can_handle(&parts)) {
-                    route
-                        .handler
-                        .run(&mut context, Request::from_parts(parts, body))
-                        .await
-                } else {
-                    router::not_found(&mut context, Request::from_parts(parts, body)).await
-                }
+                let response =
+                    if let Some(route) = ptr.2.iter().rev().find(|f| f.can_handle(&parts)) {
+                        if let Some(scope) = &route.scope {
+                            scope::require_scope(&context.user, scope)?;
+                        }
+
+                        route
+                            .handler
+                            .run(&mut context, Request::from_parts(parts, body))
+                            .await
+                    } else {
+                        router::not_found(&mut context, Request::from_parts(parts, body)).await
+                    };
+
+                // Refresh any stale remote objects we have seen in the background.
+                entity_store.queue_stale(&mut *queue_store).await;
+
+                response
             }
                 .await;
 
@@ -195,6 +294,24 @@ impl<T: StorePool> HttpService for KroegService<T> {
                         .unwrap())
                 }
 
+                Err(ServerError::BadSharedInbox) => {
+                    println!("      actor mismatch");
+
+                    Ok(http::Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from(ServerError::BadSharedInbox.to_string()))
+                        .unwrap())
+                }
+
+                Err(ServerError::MissingScope(scope)) => {
+                    println!("      missing scope {}", scope);
+
+                    Ok(http::Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from(ServerError::MissingScope(scope).to_string()))
+                        .unwrap())
+                }
+
                 Err(e) => {
                     println!("      misc err {:?}", e);
 
@@ -204,6 +321,6 @@ impl<T: StorePool> HttpService for KroegService<T> {
                         .unwrap())
                 }
             }
-        })
+        }))
     }
 }
@@ -0,0 +1,463 @@
+//! A store that keeps everything in memory, for tests and embedded use.
+//!
+//! Nothing is saved, and all connections of a pool share the same data. Queries are
+//! answered by scanning every stored object, so this is not meant for large amounts of data.
+
+use kroeg_tap::{
+    kroeg, CollectionPointer, EntityStore, QuadQuery, QueryId, QueryObject, QueueItem, QueueStore,
+    StoreError, StoreItem,
+};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use crate::{LeasedConnection, StorePool};
+
+/// The amount of items in a collection page, if no count is given.
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// How often a queue item is attempted before it is dropped.
+const MAX_ATTEMPTS: u32 = 5;
+
+const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+const RDF_LANG_STRING: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#langString";
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Object {
+    Id(String),
+    Value {
+        value: String,
+        type_id: String,
+        language: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Quad {
+    pub subject: String,
+    pub predicate: String,
+    pub object: Object,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    objects: HashMap<String, Value>,
+    quads: HashMap<String, Vec<Quad>>,
+
+    /// The items of each collection, with the sequence number they were inserted with.
+    collections: HashMap<String, Vec<(u64, String)>>,
+    sequence: u64,
+
+    queue: VecDeque<QueueItem>,
+    attempts: HashMap<i32, u32>,
+    queue_id: i32,
+}
+
+/// A pool of connections to the same in-memory store.
+#[derive(Clone, Default)]
+pub struct MemoryStorePool(Arc<Mutex<MemoryState>>);
+
+impl MemoryStorePool {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a connection to this store, without going through `StorePool::connect`.
+    pub fn connection(&self) -> MemoryConnection {
+        MemoryConnection {
+            entity_store: MemoryEntityStore(self.0.clone()),
+            queue_store: MemoryQueueStore(self.0.clone()),
+        }
+    }
+}
+
+impl StorePool for MemoryStorePool {
+    type LeasedConnection = MemoryConnection;
+
+    fn connect(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<MemoryConnection, StoreError>> + Send + 'static>> {
+        let connection = self.connection();
+
+        Box::pin(async move { Ok(connection) })
+    }
+}
+
+pub struct MemoryConnection {
+    entity_store: MemoryEntityStore,
+    queue_store: MemoryQueueStore,
+}
+
+impl LeasedConnection for MemoryConnection {
+    fn get(&mut self) -> (&mut dyn EntityStore, &mut dyn QueueStore) {
+        (&mut self.entity_store, &mut self.queue_store)
+    }
+}
+
+pub struct MemoryEntityStore(Arc<Mutex<MemoryState>>);
+
+pub struct MemoryQueueStore(Arc<Mutex<MemoryState>>);
+
+/// Converts an expanded JSON-LD value into the objects it holds. Lists are flattened.
+fn to_objects(value: &Value, objects: &mut Vec<Object>) {
+    let obj = match value {
+        Value::Object(obj) => obj,
+        Value::Array(items) => {
+            for item in items {
+                to_objects(item, objects);
+            }
+
+            return;
+        }
+
+        _ => return,
+    };
+
+    if let Some(list) = obj.get("@list") {
+        to_objects(list, objects);
+    } else if let Some(Value::String(id)) = obj.get("@id") {
+        objects.push(Object::Id(id.to_owned()));
+    } else if let Some(value) = obj.get("@value") {
+        let language = obj
+            .get("@language")
+            .and_then(|f| f.as_str())
+            .map(str::to_owned);
+
+        let type_id = match (obj.get("@type").and_then(|f| f.as_str()), &language, value) {
+            (Some(type_id), _, _) => type_id.to_owned(),
+            (None, Some(_), _) => RDF_LANG_STRING.to_owned(),
+            (None, None, Value::Bool(_)) => format!("{}boolean", XSD),
+            (None, None, Value::Number(num)) if num.is_f64() => format!("{}double", XSD),
+            (None, None, Value::Number(_)) => format!("{}integer", XSD),
+            (None, None, _) => format!("{}string", XSD),
+        };
+
+        let value = match value {
+            Value::String(value) => value.to_owned(),
+            value => value.to_string(),
+        };
+
+        objects.push(Object::Value {
+            value,
+            type_id,
+            language,
+        });
+    }
+}
+
+/// Converts a stored object into quads. The properties of its meta entity are
+/// treated as properties of the object itself, so they can be queried.
+pub(crate) fn to_quads(id: &str, json: &Value) -> Vec<Quad> {
+    let mut quads = Vec::new();
+    let nodes = match json {
+        Value::Array(nodes) => nodes.iter().collect(),
+        node => vec![node],
+    };
+
+    for node in nodes {
+        let node = match node {
+            Value::Object(node) => node,
+            _ => continue,
+        };
+
+        let subject = match node.get("@id").and_then(|f| f.as_str()) {
+            Some(subject) if subject == kroeg!(meta) => id,
+            Some(subject) => subject,
+            None => continue,
+        };
+
+        for (predicate, value) in node {
+            let predicate = match predicate as &str {
+                "@type" => RDF_TYPE,
+                predicate if predicate.starts_with('@') => continue,
+                predicate => predicate,
+            };
+
+            let mut objects = Vec::new();
+            if predicate == RDF_TYPE {
+                for kind in value.as_array().into_iter().flatten() {
+                    if let Some(kind) = kind.as_str() {
+                        objects.push(Object::Id(kind.to_owned()));
+                    }
+                }
+            } else {
+                to_objects(value, &mut objects);
+            }
+
+            for object in objects {
+                quads.push(Quad {
+                    subject: subject.to_owned(),
+                    predicate: predicate.to_owned(),
+                    object,
+                });
+            }
+        }
+    }
+
+    quads
+}
+
+/// Matches a value against part of a query, binding placeholders as needed.
+fn bind(query: &QueryId, value: &str, bindings: &mut Vec<Option<String>>) -> bool {
+    match query {
+        QueryId::Value(expected) => expected == value,
+        QueryId::Any(options) => options.iter().any(|f| f == value),
+        QueryId::Ignore => true,
+        QueryId::Placeholder(index) => {
+            let index = *index as usize;
+            if bindings.len() <= index {
+                bindings.resize(index + 1, None);
+            }
+
+            match &bindings[index] {
+                Some(bound) => bound == value,
+                None => {
+                    bindings[index] = Some(value.to_owned());
+                    true
+                }
+            }
+        }
+    }
+}
+
+fn bind_object(query: &QueryObject, object: &Object, bindings: &mut Vec<Option<String>>) -> bool {
+    match (query, object) {
+        (QueryObject::Id(query), Object::Id(id)) => bind(query, id, bindings),
+        (
+            QueryObject::Object { value, type_id },
+            Object::Value {
+                value: stored,
+                type_id: stored_type,
+                ..
+            },
+        ) => value == stored && bind(type_id, stored_type, bindings),
+
+        _ => false,
+    }
+}
+
+/// Finds all the ways the query can be matched, depth-first.
+fn run_query(
+    quads: &[&Quad],
+    query: &[QuadQuery],
+    bindings: Vec<Option<String>>,
+    results: &mut Vec<Vec<String>>,
+) {
+    let (first, rest) = match query.split_first() {
+        Some(split) => split,
+        None => {
+            let result: Vec<_> = bindings
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect();
+            if !results.contains(&result) {
+                results.push(result);
+            }
+
+            return;
+        }
+    };
+
+    for quad in quads {
+        let mut bindings = bindings.clone();
+        if bind(&first.0, &quad.subject, &mut bindings)
+            && bind(&first.1, &quad.predicate, &mut bindings)
+            && bind_object(&first.2, &quad.object, &mut bindings)
+        {
+            run_query(quads, rest, bindings, results);
+        }
+    }
+}
+
+/// Parses a collection cursor, `before-<n>` or `after-<n>`, into (is before, n).
+pub(crate) fn parse_cursor(cursor: &str) -> Option<(bool, u64)> {
+    if cursor.starts_with("before-") {
+        cursor[7..].parse().ok().map(|f| (true, f))
+    } else if cursor.starts_with("after-") {
+        cursor[6..].parse().ok().map(|f| (false, f))
+    } else {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl EntityStore for MemoryEntityStore {
+    async fn get(&mut self, path: String, _local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let json = match self.0.lock().unwrap().objects.get(&path) {
+            Some(json) => json.clone(),
+            None => return Ok(None),
+        };
+
+        Ok(Some(StoreItem::parse(&path, &json)?))
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let json = item.to_json();
+        let quads = to_quads(&path, &json);
+
+        let mut state = self.0.lock().unwrap();
+        state.quads.insert(path.to_owned(), quads);
+        state.objects.insert(path, json);
+
+        Ok(())
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        let state = self.0.lock().unwrap();
+        let quads: Vec<_> = state.quads.values().flatten().collect();
+
+        let mut results = Vec::new();
+        run_query(&quads, &query, Vec::new(), &mut results);
+
+        Ok(results)
+    }
+
+    /// Reads a page of a collection, newest items first. `after` points to the next
+    /// (older) page, and `before` to the previous (newer) one.
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let state = self.0.lock().unwrap();
+        let items: &[(u64, String)] = state
+            .collections
+            .get(&path)
+            .map(|f| f as &[_])
+            .unwrap_or(&[]);
+
+        let count = count.map(|f| f as usize).unwrap_or(DEFAULT_PAGE_SIZE);
+
+        // Items are kept oldest first, so pages are taken from the end.
+        let end = match cursor.as_ref().and_then(|f| parse_cursor(f)) {
+            Some((true, seq)) => items.iter().position(|f| f.0 >= seq).unwrap_or(items.len()),
+            Some((false, seq)) => {
+                let start = items.iter().position(|f| f.0 > seq).unwrap_or(items.len());
+                (start + count).min(items.len())
+            }
+
+            None => items.len(),
+        };
+
+        let start = end.saturating_sub(count);
+        let page = &items[start..end];
+
+        Ok(CollectionPointer {
+            items: page.iter().rev().map(|f| f.1.to_owned()).collect(),
+            before: if end < items.len() {
+                page.last().map(|f| format!("after-{}", f.0))
+            } else {
+                None
+            },
+            after: if start > 0 {
+                page.first().map(|f| format!("before-{}", f.0))
+            } else {
+                None
+            },
+            count: Some(items.len() as u32),
+        })
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let state = self.0.lock().unwrap();
+        let found = state
+            .collections
+            .get(&path)
+            .map(|f| f.iter().any(|f| f.1 == item))
+            .unwrap_or(false);
+
+        Ok(CollectionPointer {
+            items: if found { vec![item] } else { vec![] },
+            before: None,
+            after: None,
+            count: None,
+        })
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        let mut state = self.0.lock().unwrap();
+        state.sequence += 1;
+        let sequence = state.sequence;
+
+        let items = state.collections.entry(path).or_insert_with(Vec::new);
+        if !items.iter().any(|f| f.1 == item) {
+            items.push((sequence, item));
+        }
+
+        Ok(())
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let state = self.0.lock().unwrap();
+        let items: Vec<_> = state
+            .collections
+            .iter()
+            .filter(|(_, items)| items.iter().any(|f| f.1 == item))
+            .map(|(path, _)| path.to_owned())
+            .collect();
+
+        Ok(CollectionPointer {
+            count: Some(items.len() as u32),
+            items,
+            before: None,
+            after: None,
+        })
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        if let Some(items) = self.0.lock().unwrap().collections.get_mut(&path) {
+            items.retain(|f| f.1 != item);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueStore for MemoryQueueStore {
+    async fn get_item(&mut self) -> Result<Option<QueueItem>, StoreError> {
+        Ok(self.0.lock().unwrap().queue.pop_front())
+    }
+
+    async fn mark_success(&mut self, item: QueueItem) -> Result<(), StoreError> {
+        self.0.lock().unwrap().attempts.remove(&item.id);
+
+        Ok(())
+    }
+
+    /// Puts the item back at the end of the queue, unless it failed too often.
+    async fn mark_failure(&mut self, item: QueueItem) -> Result<(), StoreError> {
+        let mut state = self.0.lock().unwrap();
+        let attempts = state.attempts.entry(item.id).or_insert(0);
+        *attempts += 1;
+
+        if *attempts < MAX_ATTEMPTS {
+            state.queue.push_back(item);
+        } else {
+            state.attempts.remove(&item.id);
+        }
+
+        Ok(())
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        let mut state = self.0.lock().unwrap();
+        state.queue_id += 1;
+
+        let id = state.queue_id;
+        state.queue.push_back(QueueItem { id, event, data });
+
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use chashmap::CHashMap;
+use futures::io::AsyncReadExt;
 use http::Uri;
 use http_service::{Body, Request, Response};
 use jsonld::nodemap::Pointer;
@@ -8,13 +10,48 @@ use kroeg_tap::{
 };
 use kroeg_tap_activitypub::handlers;
 use serde_json;
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
+use crate::config::DomainPolicy;
 use crate::context::{self, SurfContextLoader};
+use crate::federation;
+use crate::idempotency::{self, Reserved};
+use crate::inbox::{ServerDeleteHandler, ServerUpdateHandler};
 use crate::request::store_all;
 use crate::router::RequestHandler;
+use crate::scope;
+use crate::state;
+use crate::store::{refetch, FetchBudget};
 use crate::ServerError;
 
+/// How long the digest of an activity with a blank node ID is remembered, to detect retried
+/// deliveries.
+const DIGEST_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Recent deliveries of activities with a blank node ID to a service, by inbox and digest
+/// of the body.
+#[derive(Default)]
+pub struct RecentDigests(CHashMap<String, Instant>);
+
+impl RecentDigests {
+    fn has_seen(&self, key: &str) -> bool {
+        match self.0.get(key) {
+            Some(ref seen) => seen.elapsed() < DIGEST_WINDOW,
+            None => false,
+        }
+    }
+
+    fn remember(&self, key: String) {
+        if self.0.len() > 10_000 {
+            self.0.retain(|_, seen| seen.elapsed() < DIGEST_WINDOW);
+        }
+
+        self.0.insert(key, Instant::now());
+    }
+}
+
 async fn prepare_delivery(
     context: &mut Context<'_, '_>,
     id: String,
@@ -156,11 +193,20 @@ async fn prepare_delivery(
                     .any(|f| f == as2!(OrderedCollection))
                     && depth < 3
                 {
+                    let max_recipients = state::of(context)?.config.fetch.max_recipients;
                     let data = context
                         .entity_store
-                        .read_collection(item.id().to_owned(), Some(99999999), None)
+                        .read_collection(item.id().to_owned(), Some(max_recipients), None)
                         .await?;
 
+                    if data.items.len() >= max_recipients as usize {
+                        println!(
+                            " - only delivering to the first {} items of {}",
+                            max_recipients,
+                            item.id()
+                        );
+                    }
+
                     for fitem in data.items {
                         audience.push((
                             depth + 1,
@@ -179,7 +225,12 @@ async fn prepare_delivery(
         }
     }
 
+    let state = state::of(context)?;
     for send_to in boxes {
+        if state.federation.is_rejected_id(&send_to) {
+            continue;
+        }
+
         let formatted = format!("{} {}", obj.id(), send_to);
         context
             .queue_store
@@ -190,6 +241,188 @@ async fn prepare_delivery(
     Ok(())
 }
 
+/// Reads the IDs from a property of an expanded JSON-LD object.
+fn expanded_ids(obj: &serde_json::Map<String, serde_json::Value>, prop: &str) -> Vec<String> {
+    obj.get(prop)
+        .and_then(|f| f.as_array())
+        .map(|f| {
+            f.iter()
+                .filter_map(|f| f.get("@id").and_then(|f| f.as_str()))
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn pointer_ids(pointers: &[Pointer]) -> Vec<String> {
+    pointers
+        .iter()
+        .filter_map(|f| match f {
+            Pointer::Id(id) => Some(id.to_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks if an activity not signed by its actor is relayed, by retrieving it from its origin
+/// and verifying that its actor lives on that same origin. The origin's copy is stored.
+async fn verify_relayed(context: &mut Context<'_, '_>, budget: &FetchBudget, id: &str) -> bool {
+    let authority = match id
+        .parse::<Uri>()
+        .ok()
+        .and_then(|f| f.authority_part().cloned())
+    {
+        Some(authority) => authority,
+        None => return false,
+    };
+
+    let state = match state::of(context) {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    let item = match refetch(context.entity_store, &state, budget, id.to_owned()).await {
+        Ok(Some(item)) => item,
+        _ => return false,
+    };
+
+    let actors = pointer_ids(&item.main()[as2!(actor)]);
+
+    !actors.is_empty()
+        && actors.iter().all(|f| {
+            f.parse::<Uri>()
+                .ok()
+                .and_then(|f| f.authority_part().cloned())
+                .as_ref()
+                == Some(&authority)
+        })
+}
+
+/// Checks if the owner of an inbox follows an actor.
+async fn owner_follows(
+    context: &mut Context<'_, '_>,
+    inbox: &str,
+    actor: &str,
+) -> Result<bool, StoreError> {
+    let query = context
+        .entity_store
+        .query(vec![QuadQuery(
+            QueryId::Placeholder(0),
+            QueryId::Value(String::from(ldp!(inbox))),
+            QueryObject::Id(QueryId::Value(inbox.to_owned())),
+        )])
+        .await?;
+
+    let mut following = HashSet::new();
+    for mut item in query {
+        let owner = match item.pop() {
+            Some(owner) => owner,
+            None => continue,
+        };
+
+        if let Some(owner) = context.entity_store.get(owner, true).await? {
+            following.extend(pointer_ids(&owner.main()[as2!(following)]));
+        }
+    }
+
+    if following.is_empty() {
+        return Ok(false);
+    }
+
+    let collections = context
+        .entity_store
+        .read_collection_inverse(actor.to_owned())
+        .await?;
+
+    Ok(collections.items.iter().any(|f| following.contains(f)))
+}
+
+/// Checks if an activity has already been delivered to an inbox.
+async fn is_delivered(
+    context: &mut Context<'_, '_>,
+    inbox: &str,
+    activity: &str,
+) -> Result<bool, StoreError> {
+    let found = context
+        .entity_store
+        .find_collection(inbox.to_owned(), activity.to_owned())
+        .await?;
+
+    Ok(found.items.iter().any(|f| f == activity))
+}
+
+/// The key an activity with a blank node ID is remembered by, as it can't be found in the
+/// inbox.
+fn digest_key(inbox: &str, body: &[u8]) -> String {
+    let digest = base64::encode_config(&Sha256::digest(body), base64::STANDARD);
+
+    format!("{} {}", inbox, digest)
+}
+
+/// The blank node ID given to an activity delivered without an ID. It is on the origin of
+/// the sender, so the activity is trusted like the other objects from there.
+fn blank_id(sender: &str, body: &[u8]) -> String {
+    let digest = base64::encode_config(&Sha256::digest(body), base64::URL_SAFE_NO_PAD);
+
+    format!("_:{}#{}", sender, digest)
+}
+
+/// Checks if a Content-Type header is one of the ActivityStreams media types, or plain
+/// JSON, which many clients send.
+fn is_activity_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+
+    mime == "application/activity+json"
+        || mime == "application/ld+json"
+        || mime == "application/json"
+}
+
+/// Reads a request body, or returns `None` if it is larger than `max_size` bytes.
+async fn read_body(mut body: Body, max_size: usize) -> Result<Option<Vec<u8>>, ServerError> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let read = body
+            .read(&mut buf)
+            .await
+            .map_err(|f| ServerError::HandlerError(f.into()))?;
+        if read == 0 {
+            return Ok(Some(data));
+        }
+
+        if data.len() + read > max_size {
+            return Ok(None);
+        }
+
+        data.extend_from_slice(&buf[..read]);
+    }
+}
+
+fn refused(status: u16, message: &'static str) -> Response {
+    http::Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// The response to a successful POST, with the ID of the activity.
+fn posted(status: u16, id: &str) -> Response {
+    http::Response::builder()
+        .status(status)
+        .header("Location", id)
+        .header(
+            "Content-Type",
+            "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
+        )
+        .body(Body::from(serde_json::json!({ "@id": id }).to_string()))
+        .unwrap()
+}
+
 enum DeliveryMode {
     LocalAndRemote,
     LocalOnly,
@@ -209,6 +442,7 @@ fn get_handler(box_type: &str) -> Option<(Vec<Box<dyn MessageHandler>>, Delivery
                 Box::new(handlers::ServerCreateHandler),
                 Box::new(handlers::ServerLikeHandler),
                 Box::new(handlers::ServerFollowHandler),
+                Box::new(ServerDeleteHandler),
             ],
             DeliveryMode::None,
             TrustMode::TrustIDs,
@@ -259,37 +493,16 @@ impl RequestHandler for PostHandler {
         request: Request,
     ) -> Result<Response, ServerError> {
         let id = format!("{}{}", context.server_base, request.uri().path());
+        let budget = FetchBudget::of_request(request.extensions());
 
-        let (_, body) = request.into_parts();
-        let body = body
-            .into_vec()
-            .await
-            .map_err(|f| ServerError::HandlerError(f.into()))?;
-        let json = serde_json::from_slice(&body).map_err(ServerError::SerdeError)?;
-
-        let expanded = expand::<SurfContextLoader>(
-            &context::apply_supplement(json),
-            &JsonLdOptions {
-                base: None,
-                compact_arrays: None,
-                expand_context: None,
-                processing_mode: None,
-            },
-        )
-        .await
-        .map_err(ServerError::ExpansionError)?;
-
-        let mut root = if let Some([serde_json::Value::Object(objdata)]) =
-            expanded.as_array().map(|f| f as &[_])
-        {
-            objdata
-                .get("@id")
-                .and_then(|f| f.as_str())
-                .map(|f| f.to_owned())
-        } else {
-            return Ok(not_found());
-        };
+        if let Some(host) = federation::requester_host(&context.user) {
+            if state::of(context)?.federation.is_rejected(&host) {
+                println!(" - rejected a post to {} from {}", id, host);
+                return Ok(refused(403, "activities from this domain are rejected"));
+            }
+        }
 
+        // The scope depends on the kind of box, so find that out before reading the body.
         let inbox = context
             .entity_store
             .get(id, true)
@@ -303,20 +516,166 @@ impl RequestHandler for PostHandler {
         };
 
         let box_type = if let [Pointer::Id(id)] = &inbox.meta()[kroeg!(box)] as &[Pointer] {
-            id
+            id.to_owned()
         } else {
             return Err(ServerError::PostToNonbox);
         };
 
-        let (handlers, delivery_mode, trust_mode) =
-            get_handler(box_type).ok_or(ServerError::PostToNonbox)?;
+        let (mut handlers, delivery_mode, trust_mode) =
+            get_handler(&box_type).ok_or(ServerError::PostToNonbox)?;
+
+        scope::require_scope(
+            &context.user,
+            if box_type == as2!(outbox) {
+                scope::WRITE_OUTBOX
+            } else {
+                scope::WRITE_INBOX
+            },
+        )?;
+
+        let (parts, body) = request.into_parts();
+        let idempotency_key = parts
+            .headers
+            .get("Idempotency-Key")
+            .and_then(|f| f.to_str().ok())
+            .map(str::to_owned);
+
+        let content_type = parts
+            .headers
+            .get("Content-Type")
+            .and_then(|f| f.to_str().ok())
+            .unwrap_or("");
+        if !is_activity_type(content_type) {
+            return Ok(refused(
+                415,
+                "activities have to be posted as ActivityStreams JSON",
+            ));
+        }
+
+        // Don't even start reading bodies that announce they're too large.
+        let max_body_size = state::of(context)?.config.post.max_body_size;
+        let length = parts
+            .headers
+            .get("Content-Length")
+            .and_then(|f| f.to_str().ok())
+            .and_then(|f| f.parse::<usize>().ok());
+        if length.map(|f| f > max_body_size) == Some(true) {
+            return Ok(refused(413, "request body is too large"));
+        }
+
+        let body = match read_body(body, max_body_size).await? {
+            Some(body) => body,
+            None => return Ok(refused(413, "request body is too large")),
+        };
+        let json = serde_json::from_slice(&body).map_err(ServerError::SerdeError)?;
+
+        let mut expanded = expand::<SurfContextLoader>(
+            &context::apply_supplement(json),
+            &JsonLdOptions {
+                base: None,
+                compact_arrays: None,
+                expand_context: None,
+                processing_mode: None,
+            },
+        )
+        .await
+        .map_err(ServerError::ExpansionError)?;
+
+        // Some servers deliver activities without an ID, which are stored with a blank node
+        //  ID instead. Retries of them are recognized by the digest of the body.
+        if let TrustMode::TrustIDs = trust_mode {
+            if let Some([serde_json::Value::Object(objdata)]) =
+                expanded.as_array_mut().map(|f| f as &mut [_])
+            {
+                if !objdata.contains_key("@id") {
+                    let id = blank_id(&context.user.subject, &body);
+                    objdata.insert("@id".to_owned(), serde_json::Value::String(id));
+                }
+            }
+        }
+
+        let (mut root, mut actors, is_delivery) =
+            if let Some([serde_json::Value::Object(objdata)]) =
+                expanded.as_array().map(|f| f as &[_])
+            {
+                (
+                    objdata
+                        .get("@id")
+                        .and_then(|f| f.as_str())
+                        .map(|f| f.to_owned()),
+                    expanded_ids(objdata, as2!(actor)),
+                    objdata
+                        .get("@type")
+                        .and_then(|f| f.as_array())
+                        .map(|f| f.iter().any(|f| f == kroeg!(DeliveryObject)))
+                        .unwrap_or(false),
+                )
+            } else {
+                return Ok(not_found());
+            };
+
+        // Clients retry POSTs when they don't see the response, so give them the response
+        //  to the first one instead of posting again.
+        let reservation = match idempotency_key {
+            Some(key) if box_type == as2!(outbox) => {
+                let window =
+                    Duration::from_secs(state::of(context)?.config.post.idempotency_window);
+
+                match idempotency::reserve(&context.user.subject, &key, window) {
+                    Reserved::New(reservation) => Some(reservation),
+                    Reserved::InProgress => {
+                        return Ok(refused(
+                            409,
+                            "a request with this Idempotency-Key is still being handled",
+                        ))
+                    }
+                    Reserved::Done { status, location } => return Ok(posted(status, &location)),
+                }
+            }
+
+            _ => None,
+        };
 
         let mut untangled = untangle(&expanded).unwrap();
+        let mut silenced = false;
+        let mut digest = None;
 
         if let TrustMode::TrustIDs = trust_mode {
-            // We are posting to an inbox (aka server-to-server). This means we can
-            //  trust anything that is on the same origin as the authorized user.
+            // We are posting to an inbox (aka server-to-server). The actor of the activity
+            //  has to be the owner of the key that signed the request, so that anything
+            //  after this can rely on the actor being authenticated.
+
+            if is_delivery {
+                // Local deliveries only contain the ID, so check the actor we have stored.
+                if let Some(root) = &root {
+                    actors = match context
+                        .entity_store
+                        .get(root.to_owned(), true)
+                        .await
+                        .map_err(ServerError::StoreError)?
+                    {
+                        Some(item) => pointer_ids(&item.main()[as2!(actor)]),
+                        None => vec![],
+                    };
+                }
+            }
+
+            if actors.is_empty() || actors.iter().any(|f| *f != context.user.subject) {
+                // The only other option is that the activity is relayed, in which case we
+                //  ignore the body and use the copy from its origin.
+                let relayed = match &root {
+                    Some(root) => verify_relayed(context, &budget, root).await,
+                    None => false,
+                };
+
+                if !relayed {
+                    return Err(ServerError::BadSharedInbox);
+                }
+
+                untangled.clear();
+            }
 
+            // This means we can trust anything that is on the same origin as the authorized user.
             let user: Uri = context.user.subject.parse().unwrap();
             let authority = user.authority_part().cloned();
 
@@ -331,6 +690,72 @@ impl RequestHandler for PostHandler {
 
                 key.and_then(|f| f.authority_part().cloned()) == authority
             });
+
+            let state = state::of(context)?;
+            match federation::host_of(&context.user.subject)
+                .and_then(|host| state.federation.domain_policy(&host))
+            {
+                Some(DomainPolicy::RejectMedia) => {
+                    for item in untangled.values_mut() {
+                        federation::strip_media(item);
+                    }
+                }
+
+                // Whether an activity is silenced depends on who the inbox belongs to, so
+                //  activities on the shared inbox are checked as they're handed out to the
+                //  inboxes of each user.
+                Some(DomainPolicy::Silence) if box_type == ldp!(inbox) => {
+                    let actor = context.user.subject.to_owned();
+                    silenced = !owner_follows(context, inbox.id(), &actor)
+                        .await
+                        .map_err(ServerError::StoreError)?;
+                }
+
+                _ => {}
+            }
+
+            // Servers retry deliveries they aren't sure about, so make sure nothing happens
+            //  twice. Activities with a blank node ID are recognized by the digest of the body.
+            let duplicate = match &root {
+                Some(root) if !root.starts_with("_:") => is_delivered(context, inbox.id(), root)
+                    .await
+                    .map_err(ServerError::StoreError)?,
+
+                _ => {
+                    let key = digest_key(inbox.id(), &body);
+                    let seen = state::of(context)?.recent_digests.has_seen(&key);
+                    digest = Some(key);
+
+                    seen
+                }
+            };
+
+            if duplicate {
+                return Ok(http::Response::builder()
+                    .status(200)
+                    .body(Body::from(serde_json::json!({ "@id": root }).to_string()))
+                    .unwrap());
+            }
+
+            // The objects of an Update are stored by its handler instead, once it knows the
+            //  actor owns them, so the versions they replace can be kept.
+            let mut updated = HashMap::new();
+            if let Some(root) = &root {
+                let is_update = untangled
+                    .get(root)
+                    .map(|f| f.main().types.iter().any(|t| t == as2!(Update)))
+                    .unwrap_or(false);
+
+                if is_update {
+                    for id in pointer_ids(&untangled[root].main()[as2!(object)]) {
+                        if let Some(item) = untangled.remove(&id) {
+                            updated.insert(id, item);
+                        }
+                    }
+                }
+            }
+
+            handlers.push(Box::new(ServerUpdateHandler::new(updated, budget)));
         } else {
             // On outboxes, however, we use any external IDs, but ignore any internal IDs,
             //  and assign our own.
@@ -355,6 +780,24 @@ impl RequestHandler for PostHandler {
         let mut user_inoutbox = inbox.id().to_owned();
         let mut root = root.unwrap();
 
+        // Activities from silenced domains are kept, but only show up for users that
+        //  follow their actor.
+        if silenced {
+            println!(
+                " - silenced {} from {} in {}",
+                root, context.user.subject, user_inoutbox
+            );
+
+            if let Some(key) = digest {
+                state::of(context)?.recent_digests.remember(key);
+            }
+
+            return Ok(http::Response::builder()
+                .status(202)
+                .body(Body::from(serde_json::json!({ "@id": root }).to_string()))
+                .unwrap());
+        }
+
         for handler in handlers {
             handler
                 .handle(context, &mut user_inoutbox, &mut root)
@@ -375,14 +818,14 @@ impl RequestHandler for PostHandler {
             .await
             .map_err(ServerError::StoreError)?;
 
-        Ok(http::Response::builder()
-            .status(201)
-            .header("Location", &root)
-            .header(
-                "Content-Type",
-                "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
-            )
-            .body(Body::from(serde_json::json!({ "@id": root }).to_string()))
-            .unwrap())
+        if let Some(key) = digest {
+            state::of(context)?.recent_digests.remember(key);
+        }
+
+        if let Some(reservation) = reservation {
+            reservation.complete(201, &root);
+        }
+
+        Ok(posted(201, &root))
     }
 }
@@ -0,0 +1,203 @@
+//! Token-bucket rate limits for incoming requests.
+//!
+//! Every request takes a token from the buckets of the subject of its bearer token, the
+//! domain that signed it and the IP address of the client. Reads, inbox writes and outbox
+//! writes each have their own buckets, and a request is refused if any of its buckets is
+//! empty. Requests without any of these aren't limited.
+//!
+//! The limits apply before the request is authenticated, as verifying a signature may make
+//! us retrieve keys and actors. So the subject and domain are the ones the request claims.
+
+use chashmap::CHashMap;
+use http::{request::Parts, Method};
+use http_service::{Body, Response};
+use jsonld::nodemap::Pointer;
+use kroeg_tap::{as2, kroeg, EntityStore, StoreError};
+use serde_json::Value;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimit;
+use crate::federation;
+use crate::signature;
+use crate::state::ServiceState;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+lazy_static::lazy_static! {
+    /// The buckets of all services, by domain, kind of request and what they limit.
+    static ref BUCKETS: CHashMap<String, Bucket> = CHashMap::new();
+}
+
+/// Buckets that haven't been used for this long are forgotten when there are many.
+const BUCKET_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Checks if a POST goes to an outbox.
+async fn is_outbox(
+    parts: &Parts,
+    store: &mut dyn EntityStore,
+    server_base: &str,
+) -> Result<bool, StoreError> {
+    let id = format!("{}{}", server_base, parts.uri.path());
+    let mut item = match store.get(id, true).await? {
+        Some(item) => item,
+        None => return Ok(false),
+    };
+
+    Ok(match &item.meta()[kroeg!(box)] as &[Pointer] {
+        [Pointer::Id(id)] => id == as2!(outbox),
+        _ => false,
+    })
+}
+
+/// Reads the IP address of the client from the header set by the reverse proxy.
+fn client_ip(parts: &Parts, header: &str) -> Option<String> {
+    if header.is_empty() {
+        return None;
+    }
+
+    parts
+        .headers
+        .get(header)
+        .and_then(|f| f.to_str().ok())
+        .and_then(|f| f.split(',').last())
+        .map(|f| f.trim().to_owned())
+        .filter(|f| !f.is_empty())
+}
+
+/// Reads the subject of a bearer token, without verifying it.
+fn claimed_subject(parts: &Parts) -> Option<String> {
+    let header = parts.headers.get("Authorization")?.to_str().ok()?;
+    let token = match header.split(' ').collect::<Vec<_>>()[..] {
+        ["Bearer", token] => token,
+        _ => return None,
+    };
+
+    let contents = token.split('.').nth(1)?;
+    let contents = base64::decode_config(contents.as_bytes(), base64::URL_SAFE_NO_PAD).ok()?;
+    let contents: Value = serde_json::from_slice(&contents).ok()?;
+
+    contents.get("sub")?.as_str().map(str::to_owned)
+}
+
+/// Reads the domain of the key a request is signed with, without verifying the signature.
+fn claimed_domain(parts: &Parts) -> Option<String> {
+    let key_id = signature::from_request(parts)?.ok()?.key_id;
+
+    if key_id.starts_with("acct:") {
+        key_id
+            .rsplit('@')
+            .next()
+            .map(|f| f.split(':').next().unwrap_or(f).to_lowercase())
+    } else {
+        federation::host_of(&key_id)
+    }
+}
+
+fn refill(bucket: &mut Bucket, limit: &RateLimit, now: Instant) {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    let rate = f64::from(limit.per_minute) / 60.0;
+
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(f64::from(limit.burst.max(1)));
+    bucket.updated = now;
+}
+
+/// Takes a token from each of the buckets, or none if one of them is empty. Returns how
+/// many seconds to wait if the request has to be refused.
+fn take(limit: &RateLimit, keys: &[String]) -> Option<u64> {
+    if BUCKETS.len() > 100_000 {
+        BUCKETS.retain(|_, bucket| bucket.updated.elapsed() < BUCKET_IDLE);
+    }
+
+    let now = Instant::now();
+    let burst = f64::from(limit.burst.max(1));
+
+    for (taken, key) in keys.iter().enumerate() {
+        // Tokens are taken while the bucket is locked, so concurrent requests can't both
+        //  take the last one.
+        let refused = Cell::new(None);
+        BUCKETS.upsert(
+            key.to_owned(),
+            || Bucket {
+                tokens: burst - 1.0,
+                updated: now,
+            },
+            |bucket| {
+                refill(bucket, limit, now);
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                } else {
+                    refused.set(Some(bucket.tokens));
+                }
+            },
+        );
+
+        if let Some(tokens) = refused.get() {
+            // Give back the tokens taken from the other buckets.
+            for key in &keys[..taken] {
+                if let Some(mut bucket) = BUCKETS.get_mut(key) {
+                    bucket.tokens = (bucket.tokens + 1.0).min(burst);
+                }
+            }
+
+            let rate = f64::from(limit.per_minute) / 60.0;
+            return Some(((1.0 - tokens) / rate).ceil().max(1.0) as u64);
+        }
+    }
+
+    None
+}
+
+/// Takes a token for a request, returning how many seconds the client has to wait if
+/// it is over one of its limits.
+pub async fn check(
+    parts: &Parts,
+    store: &mut dyn EntityStore,
+    state: &ServiceState,
+) -> Result<Option<u64>, StoreError> {
+    let config = &state.config.rate_limit;
+    let domain = &state.config.domain;
+
+    let (kind, limit) = if parts.method != Method::POST {
+        ("read", &config.read)
+    } else if is_outbox(parts, store, domain).await? {
+        ("outbox", &config.outbox)
+    } else {
+        ("inbox", &config.inbox)
+    };
+
+    if limit.per_minute == 0 {
+        return Ok(None);
+    }
+
+    let kind = format!("{} {}", domain, kind);
+    let mut keys = Vec::new();
+    if let Some(subject) = claimed_subject(parts) {
+        keys.push(format!("{} subject {}", kind, subject));
+    } else if let Some(host) = claimed_domain(parts) {
+        keys.push(format!("{} domain {}", kind, host));
+    }
+
+    if let Some(ip) = client_ip(parts, &config.ip_header) {
+        keys.push(format!("{} ip {}", kind, ip));
+    }
+
+    // One bucket for all other requests would let a single client use it up for everyone,
+    //  including the servers that retrieve our actors to verify our deliveries.
+    if keys.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(take(limit, &keys))
+}
+
+pub fn too_many_requests(retry_after: u64) -> Response {
+    http::Response::builder()
+        .status(429)
+        .header("Retry-After", retry_after.to_string())
+        .body(Body::from("too many requests, try again later"))
+        .unwrap()
+}
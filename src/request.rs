@@ -1,28 +1,254 @@
+use async_std::net::ToSocketAddrs;
+use http::StatusCode;
 use kroeg_tap::{Authorizer, EntityStore, StoreError, StoreItem};
-use serde_json::{json, Value};
-use url::Url;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use url::{Host, Url};
 
-pub async fn do_request(url: &str) -> Result<Value, StoreError> {
+use crate::client::HttpClient;
+use crate::config::OutboundConfig;
+use crate::delivery::{http_date, sign_request};
+use crate::instance::InstanceKey;
+
+/// An error returned by the remote server while retrieving a document.
+#[derive(Debug)]
+pub enum FetchError {
+    /// The server responded with a non-success status code.
+    Status(StatusCode),
+    /// The server kept redirecting.
+    TooManyRedirects,
+    /// The URL is not allowed by the outbound policy.
+    Blocked(String, &'static str),
+    /// The response is not JSON.
+    BadContentType(String),
+    /// The response is larger than allowed.
+    TooLarge,
+    /// The request took longer than the time that was left for it.
+    TimedOut,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Status(status) => write!(f, "remote responded with {}", status),
+            FetchError::TooManyRedirects => write!(f, "too many redirects"),
+            FetchError::BadContentType(kind) => write!(f, "unexpected content type {:?}", kind),
+            FetchError::TooLarge => write!(f, "response is too large"),
+            FetchError::TimedOut => write!(f, "request timed out"),
+            FetchError::Blocked(url, reason) => {
+                write!(f, "refusing to request {}: {}", url, reason)
+            }
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+/// How a service makes outbound requests.
+#[derive(Clone)]
+pub struct Outbound {
+    pub client: Arc<dyn HttpClient>,
+
+    /// The policy that all outbound requests are checked against.
+    pub policy: OutboundConfig,
+
+    /// The key of the instance actor, which retrievals are signed with.
+    pub key: Option<InstanceKey>,
+}
+
+fn is_public_v4(addr: &Ipv4Addr) -> bool {
+    let octets = addr.octets();
+
+    !(addr.is_private()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        || addr.is_unspecified()
+        || addr.is_multicast()
+        // 0.0.0.0/8, "this network"
+        || octets[0] == 0
+        // 100.64.0.0/10, carrier-grade NAT
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // 192.0.0.0/24, protocol assignments
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // 198.18.0.0/15, benchmarking
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        // 240.0.0.0/4, reserved
+        || octets[0] >= 240)
+}
+
+/// Returns the IPv4 address in an IPv6 address from one of the ranges that embed one:
+/// IPv4-mapped (`::ffff:0:0/96`), IPv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`)
+/// and 6to4 (`2002::/16`).
+fn embedded_v4(addr: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let v4 = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+
+    match addr.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(v4(high, low)),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        [0x2002, high, low, _, _, _, _, _] => Some(v4(high, low)),
+
+        // Except for :: and ::1, which are checked as IPv6 addresses.
+        [0, 0, 0, 0, 0, 0, high, low] if high != 0 || low > 1 => Some(v4(high, low)),
+
+        _ => None,
+    }
+}
+
+fn is_public_v6(addr: &Ipv6Addr) -> bool {
+    if let Some(v4) = embedded_v4(addr) {
+        return is_public_v4(&v4);
+    }
+
+    let segments = addr.segments();
+
+    !(addr.is_loopback()
+        || addr.is_unspecified()
+        || addr.is_multicast()
+        // fc00::/7, unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // fe80::/10, link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // 2001:db8::/32, documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // 64:ff9b:1::/48, local-use NAT64
+        || (segments[0] == 0x64 && segments[1] == 0xff9b && segments[2] == 1))
+}
+
+fn is_public(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => is_public_v4(addr),
+        IpAddr::V6(addr) => is_public_v6(addr),
+    }
+}
+
+/// Checks if a URL may be requested according to the outbound policy: it has to use HTTPS,
+/// not be on a denied host, and resolve only to public addresses.
+///
+/// The host is resolved again when actually requesting it, so this doesn't protect against
+/// DNS rebinding. It does keep remote documents from pointing us at internal services.
+pub async fn check_url(policy: &OutboundConfig, url: &Url) -> Result<(), FetchError> {
+    let blocked = |reason| Err(FetchError::Blocked(url.to_string(), reason));
+
+    match url.scheme() {
+        "https" => {}
+        "http" if policy.allow_http => {}
+        _ => return blocked("scheme is not allowed"),
+    }
+
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return blocked("no host"),
+    };
+
+    if policy.is_denied_host(host) {
+        return blocked("host is denied");
+    }
+
+    if policy.is_allowed_host(host) {
+        return Ok(());
+    }
+
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(addr)) => vec![IpAddr::V4(addr)],
+        Some(Host::Ipv6(addr)) => vec![IpAddr::V6(addr)],
+        _ => match (host, port).to_socket_addrs().await {
+            Ok(addrs) => addrs.map(|f| f.ip()).collect(),
+            Err(_) => return blocked("host does not resolve"),
+        },
+    };
+
+    if addrs.is_empty() {
+        return blocked("host does not resolve");
+    }
+
+    if !addrs.iter().all(is_public) {
+        return blocked("host resolves to a private address");
+    }
+
+    Ok(())
+}
+
+/// The Accept header used when retrieving ActivityStreams documents.
+pub const ACCEPT_ACTIVITY: &str = "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\", application/activity+json, application/json";
+
+/// Retrieves an ActivityStreams document.
+pub async fn do_request(outbound: &Outbound, url: &str) -> Result<Value, StoreError> {
+    fetch_json(outbound, url, ACCEPT_ACTIVITY).await
+}
+
+/// Checks if a Content-Type header describes a JSON document.
+fn is_json(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+/// Retrieves a JSON document, with a specific Accept header.
+pub async fn fetch_json(outbound: &Outbound, url: &str, accept: &str) -> Result<Value, StoreError> {
     let mut url: Url = url.parse()?;
 
     for _ in 0..3usize {
-        let mut response = surf::get(url.to_string())
-            .set_header("Accept", "application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\", application/activity+json, application/json")
-            .await?;
+        // Check every URL, so a redirect can't point us somewhere we shouldn't go.
+        check_url(&outbound.policy, &url).await?;
+
+        let mut request = http::Request::builder()
+            .method("GET")
+            .uri(url.as_str())
+            .header("Accept", accept)
+            .body(Vec::new())?;
+
+        // Sign as the instance actor, for servers that refuse unsigned requests.
+        if let Some(key) = &outbound.key {
+            request
+                .headers_mut()
+                .insert("date", http_date(SystemTime::now()).parse()?);
 
-        if let Some(header) = response.header("Location") {
-            url = url.join(header)?;
+            request = sign_request(
+                &key.key_id,
+                &key.private_key,
+                &["(request-target)", "host", "date"],
+                request,
+            )?;
+        }
+
+        let max_size = outbound.policy.max_response_size;
+        let response = outbound.client.send(request, max_size).await?;
+        let header = |name| response.headers().get(name).and_then(|f| f.to_str().ok());
+
+        if let Some(location) = header("location") {
+            url = url.join(location)?;
             continue;
         }
 
-        return match response.body_json().await {
-            Ok(data) => Ok(data),
-            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(json!({})),
-            Err(e) => Err(e.into()),
-        };
+        if !response.status().is_success() {
+            return Err(FetchError::Status(response.status()).into());
+        }
+
+        let content_type = header("content-type").unwrap_or("").to_owned();
+        if !is_json(&content_type) {
+            return Err(FetchError::BadContentType(content_type).into());
+        }
+
+        return Ok(serde_json::from_slice(response.body())?);
     }
 
-    Err("timed out".into())
+    Err(FetchError::TooManyRedirects.into())
 }
 
 pub async fn store_all(
@@ -46,3 +272,74 @@ pub async fn store_all(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(addr: &str) -> bool {
+        is_public_v4(&addr.parse().unwrap())
+    }
+
+    fn v6(addr: &str) -> bool {
+        is_public_v6(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn classifies_ipv4_addresses() {
+        for addr in &[
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.1",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!v4(addr), "{} is not public", addr);
+        }
+
+        for addr in &["1.1.1.1", "8.8.8.8", "100.128.0.1", "172.32.0.1"] {
+            assert!(v4(addr), "{} is public", addr);
+        }
+    }
+
+    #[test]
+    fn classifies_ipv6_addresses() {
+        for addr in &[
+            "::",
+            "::1",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+            "64:ff9b:1::1",
+            "::ffff:10.0.0.1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::169.254.169.254",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(!v6(addr), "{} is not public", addr);
+        }
+
+        for addr in &[
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "::8.8.8.8",
+            "64:ff9b::8.8.8.8",
+            "2002:808:808::1",
+        ] {
+            assert!(v6(addr), "{} is public", addr);
+        }
+    }
+}
@@ -24,6 +24,8 @@ pub struct Route {
     pub method: Method,
     pub is_prefix: bool,
     pub content_type: Vec<String>,
+    /// The scope a bearer token needs to use this route, if any.
+    pub scope: Option<String>,
     pub handler: Box<dyn RequestHandler>,
 }
 
@@ -35,6 +37,7 @@ impl Route {
             method: Method::GET,
             is_prefix: false,
             content_type: Vec::new(),
+            scope: None,
             handler: Box::new(handler),
         }
     }
@@ -45,6 +48,7 @@ impl Route {
             method: Method::POST,
             is_prefix: false,
             content_type: Vec::new(),
+            scope: None,
             handler: Box::new(handler),
         }
     }
@@ -55,6 +59,7 @@ impl Route {
             method: Method::GET,
             is_prefix: true,
             content_type: Vec::new(),
+            scope: None,
             handler: Box::new(handler),
         }
     }
@@ -65,10 +70,17 @@ impl Route {
             method: Method::POST,
             is_prefix: true,
             content_type: Vec::new(),
+            scope: None,
             handler: Box::new(handler),
         }
     }
 
+    /// Requires bearer tokens to have a specific scope to use this route.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = Some(scope.to_owned());
+        self
+    }
+
     /// Validates if this request can be handled by the route.
     ///
     /// This means: The method has to match, and the path has to either start with or be equal
@@ -0,0 +1,42 @@
+//! Scopes for bearer tokens.
+//!
+//! A token may carry a `scope` claim, a space-separated list of scopes like `read write:outbox`.
+//! Tokens without that claim (and HTTP signature or anonymous users) are not restricted.
+
+use kroeg_tap::User;
+
+use crate::ServerError;
+
+/// Read any object the user can see.
+pub const READ: &str = "read";
+/// Read the user's inbox.
+pub const READ_INBOX: &str = "read:inbox";
+/// Read the user's outbox.
+pub const READ_OUTBOX: &str = "read:outbox";
+/// Post to inboxes, as done by server-to-server delivery.
+pub const WRITE_INBOX: &str = "write:inbox";
+/// Post to the user's outbox.
+pub const WRITE_OUTBOX: &str = "write:outbox";
+/// Everything, including the administrative routes.
+pub const ADMIN: &str = "admin";
+
+/// Checks if the user has been granted a scope. `read` grants `read:inbox`, `admin` grants all.
+pub fn has_scope(user: &User, scope: &str) -> bool {
+    let granted = match user.claims.get("scope") {
+        Some(granted) => granted,
+        None => return true,
+    };
+
+    granted.split(' ').filter(|f| !f.is_empty()).any(|f| {
+        f == ADMIN || f == scope || (scope.starts_with(f) && scope[f.len()..].starts_with(':'))
+    })
+}
+
+/// Returns `ServerError::MissingScope` if the user has not been granted the scope.
+pub fn require_scope(user: &User, scope: &str) -> Result<(), ServerError> {
+    if has_scope(user, scope) {
+        Ok(())
+    } else {
+        Err(ServerError::MissingScope(scope.to_owned()))
+    }
+}
@@ -0,0 +1,255 @@
+//! Parsing of the HTTP Signature header.
+//!
+//! The header is a comma-separated list of `name=value` pairs, where each value is either
+//! a token or a quoted string, as described in draft-cavage-http-signatures. The same
+//! parameters can also be sent as `Authorization: Signature <params>`.
+
+use http::request::Parts;
+use std::error::Error;
+use std::fmt;
+
+/// A parsed Signature header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub key_id: String,
+    pub algorithm: Option<String>,
+    pub headers: Vec<String>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    /// The header contains no parameters.
+    Empty,
+    /// The header is not valid ASCII.
+    InvalidHeader,
+    /// Expected a parameter name at this offset.
+    ExpectedName(usize),
+    /// Expected `=` at this offset.
+    ExpectedEquals(usize),
+    /// Expected a token or quoted string at this offset.
+    ExpectedValue(usize),
+    /// Expected `,` or the end of the header at this offset.
+    ExpectedComma(usize),
+    /// A quoted string that starts at this offset is never closed.
+    UnterminatedString(usize),
+    /// A character at this offset isn't allowed in a quoted string.
+    InvalidCharacter(usize),
+    /// A parameter is given more than once.
+    DuplicateParameter(String),
+    /// A required parameter is missing.
+    MissingParameter(&'static str),
+    /// The `headers` parameter doesn't list any headers.
+    EmptyHeaders,
+    /// The `signature` parameter isn't valid base64.
+    BadSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Empty => write!(f, "signature header is empty"),
+            SignatureError::InvalidHeader => write!(f, "signature header is not valid ASCII"),
+            SignatureError::ExpectedName(at) => write!(f, "expected parameter name at {}", at),
+            SignatureError::ExpectedEquals(at) => write!(f, "expected '=' at {}", at),
+            SignatureError::ExpectedValue(at) => write!(f, "expected parameter value at {}", at),
+            SignatureError::ExpectedComma(at) => write!(f, "expected ',' at {}", at),
+            SignatureError::UnterminatedString(at) => {
+                write!(f, "quoted string starting at {} is never closed", at)
+            }
+            SignatureError::InvalidCharacter(at) => {
+                write!(f, "invalid character in quoted string at {}", at)
+            }
+            SignatureError::DuplicateParameter(name) => {
+                write!(f, "parameter {:?} is given more than once", name)
+            }
+            SignatureError::MissingParameter(name) => {
+                write!(f, "missing required parameter {:?}", name)
+            }
+            SignatureError::EmptyHeaders => write!(f, "headers parameter is empty"),
+            SignatureError::BadSignature => write!(f, "signature is not valid base64"),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+/// Checks if a byte is a `tchar`, as defined in RFC 7230.
+fn is_token(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn skip_whitespace(input: &[u8], mut pos: usize) -> usize {
+    while pos < input.len() && (input[pos] == b' ' || input[pos] == b'\t') {
+        pos += 1;
+    }
+
+    pos
+}
+
+/// Reads a quoted string that starts at `pos`, returning the unescaped contents
+/// and the offset after the closing quote.
+fn read_quoted(input: &[u8], start: usize) -> Result<(String, usize), SignatureError> {
+    let mut result = Vec::new();
+    let mut pos = start + 1;
+
+    loop {
+        match input.get(pos) {
+            None => return Err(SignatureError::UnterminatedString(start)),
+            Some(b'"') => break,
+            Some(b'\\') => match input.get(pos + 1) {
+                None => return Err(SignatureError::UnterminatedString(start)),
+                Some(&c) if c != b'\t' && (c < 0x20 || c == 0x7f) => {
+                    return Err(SignatureError::InvalidCharacter(pos + 1))
+                }
+                Some(&c) => {
+                    result.push(c);
+                    pos += 2;
+                }
+            },
+            Some(&c) if c != b'\t' && (c < 0x20 || c == 0x7f) => {
+                return Err(SignatureError::InvalidCharacter(pos))
+            }
+            Some(&c) => {
+                result.push(c);
+                pos += 1;
+            }
+        }
+    }
+
+    // Unescaping only removes backslashes, so this can't split a character.
+    match String::from_utf8(result) {
+        Ok(result) => Ok((result, pos + 1)),
+        Err(_) => Err(SignatureError::InvalidCharacter(start)),
+    }
+}
+
+/// Parses the list of parameters in a Signature header, in the order they appear.
+pub fn parse_params(input: &str) -> Result<Vec<(String, String)>, SignatureError> {
+    let input = input.as_bytes();
+    let mut params: Vec<(String, String)> = Vec::new();
+    let mut pos = skip_whitespace(input, 0);
+
+    if pos == input.len() {
+        return Err(SignatureError::Empty);
+    }
+
+    loop {
+        let name_start = pos;
+        while pos < input.len() && is_token(input[pos]) {
+            pos += 1;
+        }
+
+        if pos == name_start {
+            return Err(SignatureError::ExpectedName(pos));
+        }
+
+        let name = String::from_utf8_lossy(&input[name_start..pos]).into_owned();
+
+        pos = skip_whitespace(input, pos);
+        if input.get(pos) != Some(&b'=') {
+            return Err(SignatureError::ExpectedEquals(pos));
+        }
+
+        pos = skip_whitespace(input, pos + 1);
+        let value = if input.get(pos) == Some(&b'"') {
+            let (value, end) = read_quoted(input, pos)?;
+            pos = end;
+            value
+        } else {
+            let value_start = pos;
+            while pos < input.len() && is_token(input[pos]) {
+                pos += 1;
+            }
+
+            if pos == value_start {
+                return Err(SignatureError::ExpectedValue(pos));
+            }
+
+            String::from_utf8_lossy(&input[value_start..pos]).into_owned()
+        };
+
+        if params.iter().any(|(f, _)| *f == name) {
+            return Err(SignatureError::DuplicateParameter(name));
+        }
+
+        params.push((name, value));
+
+        pos = skip_whitespace(input, pos);
+        match input.get(pos) {
+            None => break,
+            Some(b',') => pos = skip_whitespace(input, pos + 1),
+            Some(_) => return Err(SignatureError::ExpectedComma(pos)),
+        }
+    }
+
+    Ok(params)
+}
+
+/// Parses the value of a Signature header.
+pub fn parse(input: &str) -> Result<Signature, SignatureError> {
+    let mut params = parse_params(input)?;
+    let mut take = |name: &str| {
+        params
+            .iter()
+            .position(|(f, _)| f == name)
+            .map(|f| params.remove(f).1)
+    };
+
+    let key_id = take("keyId").ok_or(SignatureError::MissingParameter("keyId"))?;
+    let signature = take("signature").ok_or(SignatureError::MissingParameter("signature"))?;
+    let algorithm = take("algorithm");
+
+    // If no headers are listed, only the Date header is signed.
+    let headers = match take("headers") {
+        Some(headers) => {
+            let headers: Vec<_> = headers
+                .split(' ')
+                .filter(|f| !f.is_empty())
+                .map(str::to_lowercase)
+                .collect();
+
+            if headers.is_empty() {
+                return Err(SignatureError::EmptyHeaders);
+            }
+
+            headers
+        }
+
+        None => vec!["date".to_owned()],
+    };
+
+    let signature =
+        base64::decode(signature.as_bytes()).map_err(|_| SignatureError::BadSignature)?;
+    if signature.is_empty() {
+        return Err(SignatureError::BadSignature);
+    }
+
+    Ok(Signature {
+        key_id,
+        algorithm,
+        headers,
+        signature,
+    })
+}
+
+/// Finds and parses the signature of a request, either from the Signature header or
+/// from `Authorization: Signature`. Returns None if the request isn't signed.
+pub fn from_request(parts: &Parts) -> Option<Result<Signature, SignatureError>> {
+    if let Some(header) = parts.headers.get("Signature") {
+        return Some(match header.to_str() {
+            Ok(header) => parse(header),
+            Err(_) => Err(SignatureError::InvalidHeader),
+        });
+    }
+
+    let header = parts.headers.get("Authorization")?.to_str().ok()?;
+    let header = header.trim_start();
+    let scheme_end = header.find(' ').unwrap_or(header.len());
+
+    if header[..scheme_end].eq_ignore_ascii_case("Signature") {
+        Some(parse(&header[scheme_end..]))
+    } else {
+        None
+    }
+}
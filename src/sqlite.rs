@@ -0,0 +1,562 @@
+//! A store backed by a single SQLite database file, for small (e.g. single-user) instances.
+//!
+//! The schema is created and migrated when the database is opened. All connections of a pool
+//! share one SQLite connection, and its calls block the current task while they run.
+
+use kroeg_tap::{
+    CollectionPointer, EntityStore, QuadQuery, QueryId, QueryObject, QueueItem, QueueStore,
+    StoreError, StoreItem,
+};
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::memory::{parse_cursor, to_quads, Object};
+use crate::{LeasedConnection, StorePool};
+
+/// The amount of items in a collection page, if no count is given.
+const DEFAULT_PAGE_SIZE: u32 = 20;
+
+/// How often a queue item is attempted before it is dropped.
+const MAX_ATTEMPTS: i64 = 8;
+
+/// How long a failed queue item waits before it is retried, in seconds.
+/// This doubles with every following failure.
+const RETRY_DELAY: i64 = 60;
+
+/// The schema migrations, in order. The amount of migrations that have been applied
+/// is kept in the `user_version` of the database. Never change a migration once it has
+/// been released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: the initial schema.
+    "
+    CREATE TABLE objects (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+
+    CREATE TABLE quads (
+        id INTEGER PRIMARY KEY,
+        object_id TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        predicate TEXT NOT NULL,
+        attribute_id TEXT,
+        value TEXT,
+        type_id TEXT,
+        language TEXT
+    );
+
+    CREATE INDEX quads_object_id ON quads (object_id);
+    CREATE INDEX quads_subject ON quads (subject, predicate);
+    CREATE INDEX quads_attribute ON quads (predicate, attribute_id);
+    CREATE INDEX quads_value ON quads (predicate, value);
+
+    CREATE TABLE collection_items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        collection TEXT NOT NULL,
+        item TEXT NOT NULL,
+        UNIQUE (collection, item)
+    );
+
+    CREATE INDEX collection_items_item ON collection_items (item);
+
+    CREATE TABLE queue_items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        event TEXT NOT NULL,
+        data TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        not_before INTEGER NOT NULL DEFAULT 0,
+        taken INTEGER NOT NULL DEFAULT 0
+    );
+    ",
+];
+
+const HAS_NEWER: &str = "SELECT 1 FROM collection_items WHERE collection = ? AND id > ? LIMIT 1";
+const HAS_OLDER: &str = "SELECT 1 FROM collection_items WHERE collection = ? AND id < ? LIMIT 1";
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|f| f.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Applies the migrations that haven't been applied to this database yet.
+fn migrate(connection: &mut Connection) -> Result<(), StoreError> {
+    let version: i64 = connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// A pool of connections to a SQLite database.
+#[derive(Clone)]
+pub struct SqliteStorePool(Arc<Mutex<Connection>>);
+
+impl SqliteStorePool {
+    /// Opens (or creates) the database at this path, and migrates it to the latest schema.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        SqliteStorePool::from_connection(Connection::open(path)?)
+    }
+
+    /// Opens a database that only lives in memory.
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        SqliteStorePool::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut connection: Connection) -> Result<Self, StoreError> {
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        migrate(&mut connection)?;
+
+        // Items that were being handled when the server stopped are tried again.
+        connection.execute("UPDATE queue_items SET taken = 0", NO_PARAMS)?;
+
+        Ok(SqliteStorePool(Arc::new(Mutex::new(connection))))
+    }
+
+    /// Returns a connection to this store, without going through `StorePool::connect`.
+    pub fn connection(&self) -> SqliteConnection {
+        SqliteConnection {
+            entity_store: SqliteEntityStore(self.0.clone()),
+            queue_store: SqliteQueueStore(self.0.clone()),
+        }
+    }
+}
+
+impl StorePool for SqliteStorePool {
+    type LeasedConnection = SqliteConnection;
+
+    fn connect(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<SqliteConnection, StoreError>> + Send + 'static>> {
+        let connection = self.connection();
+
+        Box::pin(async move { Ok(connection) })
+    }
+}
+
+pub struct SqliteConnection {
+    entity_store: SqliteEntityStore,
+    queue_store: SqliteQueueStore,
+}
+
+impl LeasedConnection for SqliteConnection {
+    fn get(&mut self) -> (&mut dyn EntityStore, &mut dyn QueueStore) {
+        (&mut self.entity_store, &mut self.queue_store)
+    }
+}
+
+pub struct SqliteEntityStore(Arc<Mutex<Connection>>);
+
+pub struct SqliteQueueStore(Arc<Mutex<Connection>>);
+
+/// Builds a SQL query out of quad queries, joining one `quads` row per quad query.
+struct QueryBuilder {
+    conditions: Vec<String>,
+    params: Vec<String>,
+    placeholders: HashMap<usize, String>,
+}
+
+impl QueryBuilder {
+    fn add(&mut self, column: String, query: &QueryId) {
+        match query {
+            QueryId::Value(value) => {
+                self.params.push(value.to_owned());
+                self.conditions.push(format!("{} = ?", column));
+            }
+
+            QueryId::Any(values) => {
+                if values.is_empty() {
+                    self.conditions.push("0".to_owned());
+                    return;
+                }
+
+                let marks = vec!["?"; values.len()].join(", ");
+                self.params.extend(values.iter().cloned());
+                self.conditions.push(format!("{} IN ({})", column, marks));
+            }
+
+            QueryId::Placeholder(index) => {
+                let index = *index as usize;
+                match self.placeholders.get(&index) {
+                    Some(bound) => self.conditions.push(format!("{} = {}", column, bound)),
+                    None => {
+                        self.placeholders.insert(index, column);
+                    }
+                }
+            }
+
+            QueryId::Ignore => {}
+        }
+    }
+
+    /// Returns the SQL, its parameters, and the amount of placeholders it selects.
+    fn build(query: &[QuadQuery]) -> Result<(String, Vec<String>, usize), StoreError> {
+        let mut builder = QueryBuilder {
+            conditions: Vec::new(),
+            params: Vec::new(),
+            placeholders: HashMap::new(),
+        };
+
+        for (i, QuadQuery(subject, predicate, object)) in query.iter().enumerate() {
+            builder.add(format!("q{}.subject", i), subject);
+            builder.add(format!("q{}.predicate", i), predicate);
+
+            match object {
+                QueryObject::Id(id) => {
+                    builder
+                        .conditions
+                        .push(format!("q{}.attribute_id IS NOT NULL", i));
+                    builder.add(format!("q{}.attribute_id", i), id);
+                }
+
+                QueryObject::Object { value, type_id } => {
+                    builder.add(format!("q{}.value", i), &QueryId::Value(value.to_owned()));
+                    builder.add(format!("q{}.type_id", i), type_id);
+                }
+
+                _ => return Err("unsupported query object".into()),
+            }
+        }
+
+        let mut indices: Vec<_> = builder.placeholders.keys().cloned().collect();
+        indices.sort();
+
+        if indices.iter().enumerate().any(|(i, f)| i != *f) {
+            return Err("query placeholders should be numbered from 0".into());
+        }
+
+        let columns: Vec<_> = indices
+            .iter()
+            .map(|f| builder.placeholders[f].to_owned())
+            .collect();
+
+        let tables: Vec<_> = (0..query.len()).map(|i| format!("quads q{}", i)).collect();
+
+        let mut sql = format!(
+            "SELECT DISTINCT {} FROM {}",
+            if columns.is_empty() {
+                "''".to_owned()
+            } else {
+                columns.join(", ")
+            },
+            tables.join(", ")
+        );
+
+        if !builder.conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&builder.conditions.join(" AND "));
+        }
+
+        Ok((sql, builder.params, columns.len()))
+    }
+}
+
+#[async_trait::async_trait]
+impl EntityStore for SqliteEntityStore {
+    async fn get(&mut self, path: String, _local: bool) -> Result<Option<StoreItem>, StoreError> {
+        let data: Option<String> = self
+            .0
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM objects WHERE id = ?",
+                params![path],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match data {
+            Some(data) => Ok(Some(StoreItem::parse(
+                &path,
+                &serde_json::from_str(&data)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
+        let json = item.to_json();
+        let quads = to_quads(&path, &json);
+
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT OR REPLACE INTO objects (id, data) VALUES (?, ?)",
+            params![path, json.to_string()],
+        )?;
+
+        transaction.execute("DELETE FROM quads WHERE object_id = ?", params![path])?;
+
+        for quad in quads {
+            let (attribute_id, value, type_id, language) = match quad.object {
+                Object::Id(id) => (Some(id), None, None, None),
+                Object::Value {
+                    value,
+                    type_id,
+                    language,
+                } => (None, Some(value), Some(type_id), language),
+            };
+
+            transaction.execute(
+                "INSERT INTO quads (object_id, subject, predicate, attribute_id, value, type_id, language)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    path,
+                    quad.subject,
+                    quad.predicate,
+                    attribute_id,
+                    value,
+                    type_id,
+                    language
+                ],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (sql, params, columns) = QueryBuilder::build(&query)?;
+
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection.prepare(&sql)?;
+
+        // Without placeholders, each result is empty, and only shows the query matched.
+        let results = statement
+            .query_map(&params, |row| {
+                (0..columns)
+                    .map(|i| row.get(i))
+                    .collect::<Result<Vec<String>, _>>()
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(results)
+    }
+
+    /// Reads a page of a collection, newest items first. `after` points to the next
+    /// (older) page, and `before` to the previous (newer) one.
+    async fn read_collection(
+        &mut self,
+        path: String,
+        count: Option<u32>,
+        cursor: Option<String>,
+    ) -> Result<CollectionPointer, StoreError> {
+        let count = count.unwrap_or(DEFAULT_PAGE_SIZE) as i64;
+        let connection = self.0.lock().unwrap();
+
+        let mut items: Vec<(i64, String)> = {
+            let (sql, bound) = match cursor.as_ref().and_then(|f| parse_cursor(f)) {
+                Some((true, id)) => (
+                    "SELECT id, item FROM collection_items WHERE collection = ? AND id < ?
+                        ORDER BY id DESC LIMIT ?",
+                    id as i64,
+                ),
+                Some((false, id)) => (
+                    "SELECT id, item FROM collection_items WHERE collection = ? AND id > ?
+                        ORDER BY id ASC LIMIT ?",
+                    id as i64,
+                ),
+                None => (
+                    "SELECT id, item FROM collection_items WHERE collection = ? AND id < ?
+                        ORDER BY id DESC LIMIT ?",
+                    i64::max_value(),
+                ),
+            };
+
+            let mut statement = connection.prepare(sql)?;
+            let rows = statement.query_map(params![path, bound, count], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+            let items = rows.collect::<Result<_, _>>()?;
+            items
+        };
+
+        // Pages are always returned newest first.
+        items.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let has = |sql: &str, id: i64| -> Result<bool, StoreError> {
+            Ok(connection
+                .query_row(sql, params![path, id], |_| Ok(()))
+                .optional()?
+                .is_some())
+        };
+
+        let before = match items.first() {
+            Some((id, _)) if has(HAS_NEWER, *id)? => Some(format!("after-{}", id)),
+            _ => None,
+        };
+
+        let after = match items.last() {
+            Some((id, _)) if has(HAS_OLDER, *id)? => Some(format!("before-{}", id)),
+            _ => None,
+        };
+
+        let total: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM collection_items WHERE collection = ?",
+            params![path],
+            |row| row.get(0),
+        )?;
+
+        Ok(CollectionPointer {
+            items: items.into_iter().map(|f| f.1).collect(),
+            before,
+            after,
+            count: Some(total as u32),
+        })
+    }
+
+    async fn find_collection(
+        &mut self,
+        path: String,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let found = self
+            .0
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM collection_items WHERE collection = ? AND item = ?",
+                params![path, item],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+
+        Ok(CollectionPointer {
+            items: if found { vec![item] } else { vec![] },
+            before: None,
+            after: None,
+            count: None,
+        })
+    }
+
+    async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.0.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO collection_items (collection, item) VALUES (?, ?)",
+            params![path, item],
+        )?;
+
+        Ok(())
+    }
+
+    async fn read_collection_inverse(
+        &mut self,
+        item: String,
+    ) -> Result<CollectionPointer, StoreError> {
+        let connection = self.0.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT collection FROM collection_items WHERE item = ?")?;
+
+        let items: Vec<String> = statement
+            .query_map(params![item], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(CollectionPointer {
+            count: Some(items.len() as u32),
+            items,
+            before: None,
+            after: None,
+        })
+    }
+
+    async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
+        self.0.lock().unwrap().execute(
+            "DELETE FROM collection_items WHERE collection = ? AND item = ?",
+            params![path, item],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl QueueStore for SqliteQueueStore {
+    async fn get_item(&mut self) -> Result<Option<QueueItem>, StoreError> {
+        let mut connection = self.0.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        let item = transaction
+            .query_row(
+                "SELECT id, event, data FROM queue_items WHERE taken = 0 AND not_before <= ?
+                    ORDER BY id LIMIT 1",
+                params![now()],
+                |row| {
+                    Ok(QueueItem {
+                        id: row.get(0)?,
+                        event: row.get(1)?,
+                        data: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        if let Some(item) = &item {
+            transaction.execute(
+                "UPDATE queue_items SET taken = 1 WHERE id = ?",
+                params![item.id],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(item)
+    }
+
+    async fn mark_success(&mut self, item: QueueItem) -> Result<(), StoreError> {
+        self.0
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM queue_items WHERE id = ?", params![item.id])?;
+
+        Ok(())
+    }
+
+    /// Retries the item later with an exponential backoff, unless it failed too often.
+    async fn mark_failure(&mut self, item: QueueItem) -> Result<(), StoreError> {
+        let connection = self.0.lock().unwrap();
+        let attempts: i64 = connection.query_row(
+            "SELECT attempts FROM queue_items WHERE id = ?",
+            params![item.id],
+            |row| row.get(0),
+        )?;
+
+        if attempts + 1 >= MAX_ATTEMPTS {
+            connection.execute("DELETE FROM queue_items WHERE id = ?", params![item.id])?;
+        } else {
+            connection.execute(
+                "UPDATE queue_items SET attempts = ?, not_before = ?, taken = 0 WHERE id = ?",
+                params![attempts + 1, now() + (RETRY_DELAY << attempts), item.id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    async fn add(&mut self, event: String, data: String) -> Result<(), StoreError> {
+        self.0.lock().unwrap().execute(
+            "INSERT INTO queue_items (event, data) VALUES (?, ?)",
+            params![event, data],
+        )?;
+
+        Ok(())
+    }
+}
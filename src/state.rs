@@ -0,0 +1,105 @@
+//! The state of a running service, shared by the requests and deliveries it handles.
+//!
+//! `kroeg_tap::Context` has no room for anything of our own, so every service registers
+//! its state under its domain, and code that only has a `Context` finds it again through
+//! `server_base`. Services with different domains never see each other's state.
+
+use kroeg_tap::Context;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, RwLock, Weak};
+
+use crate::cache::EntityCache;
+use crate::config::ServerConfig;
+use crate::federation::FederationPolicy;
+use crate::post::RecentDigests;
+use crate::request::Outbound;
+use crate::store::FailedFetches;
+
+pub struct ServiceState {
+    pub config: ServerConfig,
+
+    /// How this service makes outbound requests.
+    pub outbound: Outbound,
+
+    /// How this service treats other servers.
+    pub federation: FederationPolicy,
+
+    /// The objects this service has read recently, if caching is enabled. Everything that
+    /// writes to the store of this service has to go through it, so it is kept up to date.
+    pub cache: Option<EntityCache>,
+
+    /// The remote objects this service failed to retrieve, which it backs off from.
+    pub failed_fetches: FailedFetches,
+
+    /// The deliveries of activities with a blank node ID this service has seen recently.
+    pub recent_digests: RecentDigests,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    /// No service with the domain of a context is running, e.g. because the context
+    /// wasn't made by `KroegService`.
+    NotRunning(String),
+
+    /// Another service with the same domain is running already.
+    AlreadyRunning(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotRunning(base) => write!(f, "no service is running for {}", base),
+            StateError::AlreadyRunning(base) => {
+                write!(f, "a service is running for {} already", base)
+            }
+        }
+    }
+}
+
+impl Error for StateError {}
+
+lazy_static::lazy_static! {
+    /// The running services, by domain.
+    static ref SERVICES: RwLock<HashMap<String, Weak<ServiceState>>> = RwLock::new(HashMap::new());
+}
+
+impl ServiceState {
+    /// Creates the state of a service and registers it under its domain. Fails if another
+    /// service with the same domain is still running.
+    pub fn register(
+        config: ServerConfig,
+        outbound: Outbound,
+    ) -> Result<Arc<ServiceState>, StateError> {
+        let mut services = SERVICES.write().unwrap();
+        services.retain(|_, f| f.strong_count() > 0);
+        if services.contains_key(&config.domain) {
+            return Err(StateError::AlreadyRunning(config.domain));
+        }
+
+        let state = Arc::new(ServiceState {
+            federation: FederationPolicy::new(&config),
+            cache: EntityCache::from_config(&config.cache),
+            failed_fetches: FailedFetches::default(),
+            recent_digests: RecentDigests::default(),
+            config,
+            outbound,
+        });
+
+        services.insert(state.config.domain.to_owned(), Arc::downgrade(&state));
+
+        Ok(state)
+    }
+}
+
+/// Finds the state of the running service with this domain.
+pub fn for_base(base: &str) -> Option<Arc<ServiceState>> {
+    SERVICES.read().unwrap().get(base).and_then(Weak::upgrade)
+}
+
+/// Returns the state of the service a context was made by.
+pub fn of(context: &Context<'_, '_>) -> Result<Arc<ServiceState>, StateError> {
+    for_base(&context.server_base)
+        .ok_or_else(|| StateError::NotRunning(context.server_base.to_owned()))
+}
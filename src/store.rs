@@ -1,28 +1,352 @@
-use http::Uri;
+use async_std::future::timeout;
+use chashmap::CHashMap;
+use futures::channel::oneshot;
+use http::{Extensions, StatusCode, Uri};
+use jsonld::nodemap::{Pointer, Value as NValue};
 use jsonld::{expand, JsonLdOptions};
 use kroeg_tap::{
-    as2, untangle, CollectionPointer, DefaultAuthorizer, EntityStore, QuadQuery, StoreError,
-    StoreItem,
+    as2, kroeg, untangle, CollectionPointer, DefaultAuthorizer, EntityStore, QuadQuery, QueueStore,
+    StoreError, StoreItem,
 };
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::config::{DomainPolicy, FetchConfig};
 use crate::context::{self, SurfContextLoader};
-use crate::request::{do_request, store_all};
+use crate::federation;
+use crate::request::{do_request, store_all, FetchError};
+use crate::state::ServiceState;
+
+/// The most stale objects that are remembered for a refresh at once.
+const MAX_STALE: usize = 256;
+
+/// The most references whose depth is remembered by a fetch budget.
+const MAX_TRACKED_REFERENCES: usize = 4096;
+
+/// How long a failed retrieval is remembered after the first failure. This doubles
+/// with every following failure, up to `MAX_FAILURE_BACKOFF`.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static::lazy_static! {
+    /// Remote objects that are being retrieved right now, by the domain of the service
+    /// retrieving them, with the requests waiting for them. Services have their own stores,
+    /// so they can't wait for each other's retrievals.
+    static ref IN_FLIGHT: Mutex<HashMap<(String, String), Vec<oneshot::Sender<()>>>> = Mutex::new(HashMap::new());
+}
+
+/// The remote objects a service failed to retrieve: (amount of failures, retry after).
+#[derive(Default)]
+pub struct FailedFetches(CHashMap<String, (u32, Instant)>);
+
+impl FailedFetches {
+    /// Checks if retrieving this object has failed recently.
+    fn has_failed_recently(&self, path: &str) -> bool {
+        match self.0.get(path) {
+            Some(ref failure) => failure.1 > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// Remembers a failed retrieval, backing off exponentially on repeated failures.
+    fn record(&self, path: &str) {
+        if self.0.len() > 10_000 {
+            let now = Instant::now();
+            self.0.retain(|_, failure| failure.1 > now);
+        }
+
+        let failures = self.0.get(path).map(|f| f.0).unwrap_or(0) + 1;
+        let backoff = FAILURE_BACKOFF
+            .checked_mul(1 << failures.min(16).saturating_sub(1))
+            .unwrap_or(MAX_FAILURE_BACKOFF)
+            .min(MAX_FAILURE_BACKOFF);
+
+        self.0
+            .insert(path.to_owned(), (failures, Instant::now() + backoff));
+    }
+
+    fn forget(&self, path: &str) {
+        self.0.remove(path);
+    }
+}
+
+/// Checks if a retrieval failed because of the remote server. Requests refused by our own
+/// policy don't count, as the remote server did nothing wrong.
+fn is_remote_failure(error: &StoreError) -> bool {
+    match error.downcast_ref::<FetchError>() {
+        Some(FetchError::Blocked(..)) => false,
+        _ => true,
+    }
+}
+
+/// Marks a remote object as being retrieved. Once dropped, everyone waiting for it
+/// is woken up, whether the retrieval succeeded or not.
+struct Flight((String, String));
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+enum Boarding {
+    /// Nobody is retrieving this object yet, so this request has to.
+    Leader(Flight),
+
+    /// Another request is retrieving this object already.
+    Follower(oneshot::Receiver<()>),
+}
+
+/// Joins the retrieval of a remote object, so concurrent requests to a service for the
+/// same object share a single retrieval.
+fn board(base: &str, path: &str) -> Boarding {
+    let key = (base.to_owned(), path.to_owned());
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+
+    match in_flight.get_mut(&key) {
+        Some(waiting) => {
+            let (sender, receiver) = oneshot::channel();
+            waiting.push(sender);
+
+            Boarding::Follower(receiver)
+        }
+
+        None => {
+            in_flight.insert(key.clone(), Vec::new());
+
+            Boarding::Leader(Flight(key))
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct RetrievingEntityStore<T>(T, String);
+struct BudgetState {
+    started: Instant,
+    fetches: u32,
+    depths: HashMap<String, u32>,
+    exhausted: bool,
+}
+
+impl BudgetState {
+    fn new() -> Self {
+        BudgetState {
+            started: Instant::now(),
+            fetches: 0,
+            depths: HashMap::new(),
+            exhausted: false,
+        }
+    }
+}
+
+/// Limits the remote objects retrieved while handling a single request, so one document
+/// can't make us retrieve an unbounded amount of other documents.
+///
+/// When the budget runs out, remote objects that aren't stored yet are treated as missing.
+#[derive(Clone, Debug)]
+pub struct FetchBudget(Arc<Mutex<BudgetState>>);
+
+impl Default for FetchBudget {
+    fn default() -> Self {
+        FetchBudget(Arc::new(Mutex::new(BudgetState::new())))
+    }
+}
+
+impl FetchBudget {
+    /// Takes the budget from the extensions of a request, where `KroegService` and the
+    /// delivery queue put it, or a full one if it has none.
+    pub fn of_request(extensions: &Extensions) -> FetchBudget {
+        extensions.get::<FetchBudget>().cloned().unwrap_or_default()
+    }
+
+    /// Starts over with a full budget, e.g. for the next item in the queue.
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = BudgetState::new();
+    }
+
+    /// Takes one retrieval from the budget, returning the depth of the object,
+    /// or None if the budget doesn't allow retrieving it.
+    fn spend(&self, config: &FetchConfig, path: &str) -> Option<u32> {
+        let mut state = self.0.lock().unwrap();
+        let depth = state.depths.get(path).cloned().unwrap_or(0);
+
+        let reason = if state.fetches >= config.max_fetches {
+            "too many objects retrieved"
+        } else if state.started.elapsed() >= Duration::from_secs(config.max_duration) {
+            "out of time"
+        } else if depth > config.max_depth {
+            // Only this reference is too deep, others may still be retrieved.
+            println!(" - not retrieving {}: too deep ({})", path, depth);
+            return None;
+        } else {
+            state.fetches += 1;
+            return Some(depth);
+        };
+
+        if !state.exhausted {
+            println!(
+                " - fetch budget exhausted ({}), not retrieving {}",
+                reason, path
+            );
+            state.exhausted = true;
+        }
+
+        None
+    }
+
+    /// Returns how much time is left for retrievals.
+    fn remaining(&self, config: &FetchConfig) -> Duration {
+        Duration::from_secs(config.max_duration)
+            .checked_sub(self.0.lock().unwrap().started.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Remembers the depth of the objects referenced by a retrieved object.
+    fn add_references(&self, references: Vec<String>, depth: u32) {
+        let mut state = self.0.lock().unwrap();
+
+        for reference in references {
+            if state.depths.len() >= MAX_TRACKED_REFERENCES {
+                break;
+            }
+
+            state.depths.entry(reference).or_insert(depth);
+        }
+    }
+}
+
+/// An entity store that retrieves remote objects that aren't stored yet.
+///
+/// Remote objects that are older than their TTL are still returned, but are remembered
+/// so they can be refreshed in the background, see `take_stale`.
+pub struct RetrievingEntityStore<T> {
+    store: T,
+    state: Arc<ServiceState>,
+    stale: Vec<String>,
+    budget: FetchBudget,
+}
 
 impl<T: EntityStore> RetrievingEntityStore<T> {
-    pub fn new(store: T, base: String) -> Self {
-        RetrievingEntityStore(store, base)
+    pub fn new(store: T, state: Arc<ServiceState>) -> Self {
+        RetrievingEntityStore {
+            store,
+            state,
+            stale: Vec::new(),
+            budget: FetchBudget::default(),
+        }
+    }
+
+    /// Returns the fetch budget of this store. Clones share the same budget.
+    pub fn budget(&self) -> FetchBudget {
+        self.budget.clone()
+    }
+
+    /// Queues a refresh of the stale objects that have been read. Failures are only
+    /// logged, as the request itself went fine.
+    ///
+    /// The objects are marked as retrieved now, so they aren't queued again by every
+    /// request until the refresh is done. A refresh that fails is tried again once they
+    /// are stale again.
+    pub async fn queue_stale(&mut self, queue: &mut dyn QueueStore) {
+        for id in std::mem::replace(&mut self.stale, Vec::new()) {
+            if let Err(e) = self.queue_refresh(queue, &id).await {
+                println!(" - failed to queue a refresh of {}: {}", id, e);
+            }
+        }
+    }
+
+    async fn queue_refresh(
+        &mut self,
+        queue: &mut dyn QueueStore,
+        id: &str,
+    ) -> Result<(), StoreError> {
+        let mut item = match self.store.get(id.to_owned(), true).await? {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+
+        set_fetched_at(&mut item, now());
+        self.store.put(id.to_owned(), &mut item).await?;
+
+        queue.add("refresh".to_owned(), id.to_owned()).await
+    }
+
+    fn check_stale(&mut self, item: &mut StoreItem) {
+        if !is_remote(&self.state.config.domain, item.id())
+            || self.stale.iter().any(|f| f == item.id())
+        {
+            return;
+        }
+
+        let ttl = self.state.config.fetch.ttl_for(&item.main().types);
+        let is_fresh = match fetched_at(item) {
+            Some(fetched) => now().saturating_sub(fetched) < ttl.as_secs(),
+            None => false,
+        };
+
+        if !is_fresh && self.stale.len() < MAX_STALE {
+            self.stale.push(item.id().to_owned());
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
+
+/// Returns when a remote object was last retrieved, in seconds since the epoch.
+fn fetched_at(item: &mut StoreItem) -> Option<u64> {
+    match &item.meta()[kroeg!(fetched)] as &[Pointer] {
+        [Pointer::Value(NValue { value, .. })] => value.as_u64(),
+        _ => None,
+    }
+}
+
+fn set_fetched_at(item: &mut StoreItem, at: u64) {
+    item.meta()[kroeg!(fetched)] = vec![Pointer::Value(NValue {
+        value: json!(at),
+        type_id: Some("http://www.w3.org/2001/XMLSchema#integer".to_owned()),
+        language: None,
+    })];
+}
+
+/// Collects the IDs an expanded JSON-LD document refers to.
+fn collect_references(value: &Value, references: &mut Vec<String>) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                collect_references(item, references);
+            }
+        }
+
+        Value::Object(obj) => {
+            for (key, value) in obj {
+                match (key as &str, value) {
+                    ("@id", Value::String(id)) => {
+                        if !id.starts_with("_:") {
+                            references.push(id.to_owned());
+                        }
+                    }
+
+                    _ => collect_references(value, references),
+                }
+            }
+        }
+
+        _ => {}
     }
 }
 
+/// Expands a retrieved document and unflattens it into objects, returning the objects
+/// on the same origin as the document, and the IDs the document refers to.
 async fn expand_and_unflatten(
     id: String,
     data: Value,
-) -> Result<HashMap<String, StoreItem>, StoreError> {
+) -> Result<(HashMap<String, StoreItem>, Vec<String>), StoreError> {
     let authority = id.parse::<Uri>().ok().map(|f| f.authority_part().cloned());
     let expanded = expand::<SurfContextLoader>(
         &context::apply_supplement(data),
@@ -35,6 +359,9 @@ async fn expand_and_unflatten(
     )
     .await?;
 
+    let mut references = Vec::new();
+    collect_references(&expanded, &mut references);
+
     let mut untangled = untangle(&expanded)?;
     untangled.retain(|key, _| {
         let my_authority = if key.starts_with("_:") {
@@ -50,25 +377,129 @@ async fn expand_and_unflatten(
             == authority
     });
 
-    Ok(untangled)
+    Ok((untangled, references))
 }
 
-async fn retrieve_and_store(item: String, store: &mut dyn EntityStore) -> Result<(), StoreError> {
-    let response = do_request(&item).await?;
-    let flattened = expand_and_unflatten(item, response).await?;
+/// Retrieves a remote object and stores it, returning the IDs it refers to.
+async fn retrieve_and_store(
+    item: String,
+    store: &mut dyn EntityStore,
+    state: &ServiceState,
+) -> Result<Vec<String>, StoreError> {
+    if state.federation.is_rejected_id(&item) {
+        return Err(FetchError::Blocked(item, "domain is rejected").into());
+    }
+
+    let reject_media = federation::host_of(&item)
+        .and_then(|host| state.federation.domain_policy(&host))
+        == Some(DomainPolicy::RejectMedia);
+
+    // Keep the link to the earlier versions of the object, which only this server knows of.
+    let revisions = match store.get(item.to_owned(), true).await? {
+        Some(stored) => stored.main()[kroeg!(revisions)].clone(),
+        None => Vec::new(),
+    };
+
+    let fetched = now();
+    let response = match do_request(&state.outbound, &item).await {
+        Ok(response) => response,
+
+        // The object is gone, so remember it as such.
+        Err(e) => match e.downcast_ref::<FetchError>() {
+            Some(FetchError::Status(StatusCode::GONE)) => json!({
+                "@id": item,
+                "@type": [as2!(Tombstone)]
+            }),
+            _ => return Err(e),
+        },
+    };
+
+    let (flattened, references) = expand_and_unflatten(item.to_owned(), response).await?;
 
     store_all(
         store,
         &DefaultAuthorizer,
-        flattened.into_iter().map(|(_, a)| a).collect(),
+        flattened
+            .into_iter()
+            .map(|(_, mut a)| {
+                set_fetched_at(&mut a, fetched);
+                if a.id() == item && !revisions.is_empty() {
+                    a.main_mut()[kroeg!(revisions)] = revisions.clone();
+                }
+
+                if reject_media {
+                    federation::strip_media(&mut a);
+                }
+
+                a
+            })
+            .collect(),
     )
-    .await
+    .await?;
+
+    Ok(references)
+}
+
+/// Checks if an ID points at something that can be retrieved from a remote server.
+fn is_remote(base: &str, path: &str) -> bool {
+    // Check for as:tag because `tag:abcd` will be deserialized as `as:tagabcd`. Fun.
+    !(path.starts_with("_:")
+        || path.starts_with(base)
+        || path.starts_with(as2!(tag))
+        || path == as2!(Public))
+}
+
+/// Retrieves a remote object again, replacing the stored copy if there is one.
+/// Local objects are returned as stored. Use this to force a fresh copy of an object.
+///
+/// This takes from the fetch budget of the request, and backs off from objects that
+/// failed to be retrieved, like any other retrieval.
+pub async fn refetch(
+    store: &mut dyn EntityStore,
+    state: &ServiceState,
+    budget: &FetchBudget,
+    path: String,
+) -> Result<Option<StoreItem>, StoreError> {
+    if is_remote(&state.config.domain, &path) {
+        if state.failed_fetches.has_failed_recently(&path) {
+            return Err(format!("retrieving {} failed recently", path).into());
+        }
+
+        let depth = match budget.spend(&state.config.fetch, &path) {
+            Some(depth) => depth,
+            None => return Err(format!("no fetch budget left to retrieve {}", path).into()),
+        };
+
+        let remaining = budget.remaining(&state.config.fetch);
+        let references =
+            match timeout(remaining, retrieve_and_store(path.clone(), store, state)).await {
+                Ok(Ok(references)) => references,
+                Ok(Err(e)) => {
+                    if is_remote_failure(&e) {
+                        state.failed_fetches.record(&path);
+                    }
+
+                    return Err(e);
+                }
+                // The budget of this request ran out, which says nothing about the remote.
+                Err(_) => return Err(FetchError::TimedOut.into()),
+            };
+
+        state.failed_fetches.forget(&path);
+        budget.add_references(references, depth + 1);
+    }
+
+    store.get(path, true).await
 }
 
 #[async_trait::async_trait]
 impl<T: EntityStore> EntityStore for RetrievingEntityStore<T> {
     async fn get(&mut self, path: String, local: bool) -> Result<Option<StoreItem>, StoreError> {
-        if let Some(item) = self.0.get(path.clone(), local).await? {
+        if let Some(mut item) = self.store.get(path.clone(), local).await? {
+            if !local {
+                self.check_stale(&mut item);
+            }
+
             return Ok(Some(item));
         }
 
@@ -76,11 +507,6 @@ impl<T: EntityStore> EntityStore for RetrievingEntityStore<T> {
             return Ok(None);
         }
 
-        // Check for as:tag because `tag:abcd` will be deserialized as `as:tagabcd`. Fun.
-        if path.starts_with("_:") || path.starts_with(&self.1) || path.starts_with(as2!(tag)) {
-            return Ok(None);
-        }
-
         if path == as2!(Public) {
             return Ok(StoreItem::parse(
                 as2!(Public),
@@ -92,17 +518,77 @@ impl<T: EntityStore> EntityStore for RetrievingEntityStore<T> {
             .ok());
         }
 
-        retrieve_and_store(path.clone(), &mut self.0).await?;
+        if !is_remote(&self.state.config.domain, &path) {
+            return Ok(None);
+        }
+
+        if self.state.federation.is_rejected_id(&path)
+            || self.state.failed_fetches.has_failed_recently(&path)
+        {
+            return Ok(None);
+        }
+
+        let _flight = match board(&self.state.config.domain, &path) {
+            Boarding::Leader(flight) => flight,
+
+            // The sender is dropped once the other retrieval is done, which leaves the
+            //  object in the store if it succeeded.
+            Boarding::Follower(receiver) => {
+                let _ = receiver.await;
+
+                return self.store.get(path, local).await;
+            }
+        };
+
+        // Another retrieval may have finished between the first check and boarding.
+        if let Some(item) = self.store.get(path.clone(), local).await? {
+            return Ok(Some(item));
+        }
+
+        let depth = match self.budget.spend(&self.state.config.fetch, &path) {
+            Some(depth) => depth,
+            None => return Ok(None),
+        };
+
+        // A single slow server shouldn't be able to take more time than the budget allows.
+        let remaining = self.budget.remaining(&self.state.config.fetch);
+        let retrieval = retrieve_and_store(path.clone(), &mut self.store, &self.state);
+        match timeout(remaining, retrieval).await {
+            Ok(Ok(references)) => self.budget.add_references(references, depth + 1),
+
+            // The budget of this request ran out, which says nothing about the remote.
+            Err(_) => {
+                println!(" - failed to retrieve {}: {}", path, FetchError::TimedOut);
+
+                return Ok(None);
+            }
+
+            Ok(Err(e)) => {
+                println!(" - failed to retrieve {}: {}", path, e);
+                if is_remote_failure(&e) {
+                    self.state.failed_fetches.record(&path);
+                }
+
+                return Ok(None);
+            }
+        }
+
+        let item = self.store.get(path.clone(), local).await?;
+        if item.is_none() {
+            self.state.failed_fetches.record(&path);
+        } else {
+            self.state.failed_fetches.forget(&path);
+        }
 
-        self.0.get(path, local).await
+        Ok(item)
     }
 
     async fn put(&mut self, path: String, item: &mut StoreItem) -> Result<(), StoreError> {
-        self.0.put(path, item).await
+        self.store.put(path, item).await
     }
 
     async fn query(&mut self, query: Vec<QuadQuery>) -> Result<Vec<Vec<String>>, StoreError> {
-        self.0.query(query).await
+        self.store.query(query).await
     }
 
     async fn read_collection(
@@ -111,7 +597,7 @@ impl<T: EntityStore> EntityStore for RetrievingEntityStore<T> {
         count: Option<u32>,
         cursor: Option<String>,
     ) -> Result<CollectionPointer, StoreError> {
-        self.0.read_collection(path, count, cursor).await
+        self.store.read_collection(path, count, cursor).await
     }
 
     async fn find_collection(
@@ -119,21 +605,21 @@ impl<T: EntityStore> EntityStore for RetrievingEntityStore<T> {
         path: String,
         item: String,
     ) -> Result<CollectionPointer, StoreError> {
-        self.0.find_collection(path, item).await
+        self.store.find_collection(path, item).await
     }
 
     async fn insert_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
-        self.0.insert_collection(path, item).await
+        self.store.insert_collection(path, item).await
     }
 
     async fn read_collection_inverse(
         &mut self,
         item: String,
     ) -> Result<CollectionPointer, StoreError> {
-        self.0.read_collection_inverse(item).await
+        self.store.read_collection_inverse(item).await
     }
 
     async fn remove_collection(&mut self, path: String, item: String) -> Result<(), StoreError> {
-        self.0.remove_collection(path, item).await
+        self.store.remove_collection(path, item).await
     }
 }
@@ -1,11 +1,30 @@
+use chashmap::CHashMap;
 use http::Uri;
 use http_service::{Body, Request, Response};
 use jsonld::nodemap::{Pointer, Value};
-use kroeg_tap::{as2, kroeg, Context, QuadQuery, QueryId, QueryObject, StoreItem};
+use kroeg_tap::{as2, kroeg, Context, QuadQuery, QueryId, QueryObject, StoreError, StoreItem};
 use serde_json::{json, Value as JValue};
+use std::time::{Duration, Instant};
+use url::form_urlencoded::byte_serialize;
 
+use crate::federation;
+use crate::request::{fetch_json, Outbound};
 use crate::{router::RequestHandler, router::Route, ServerError};
 
+lazy_static::lazy_static! {
+    /// Accounts that have been resolved, or failed to, and until when that is remembered.
+    static ref ACCOUNT_MAP: CHashMap<String, (Option<String>, Instant)> = CHashMap::new();
+}
+
+/// How long a resolved account is cached.
+const ACCOUNT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long an account that couldn't be resolved is cached.
+const FAILURE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The most accounts that are cached at once.
+const MAX_ACCOUNTS: usize = 10_000;
+
 /// Extracts a query in the shape of e.g. resource=acct:a@b&other=aaaa into ("a", "b")
 fn extract_acct<'a>(query: &'a str) -> Option<(&'a str, &'a str)> {
     for spl in query.split('&') {
@@ -112,6 +131,108 @@ impl RequestHandler for WebfingerHandler {
     }
 }
 
+/// Splits an `acct:user@host` URI into ("user", "host").
+fn split_acct(acct: &str) -> Option<(&str, &str)> {
+    let acct = acct.trim_start_matches("acct:");
+    let at = acct.rfind('@')?;
+    let (user, host) = (&acct[..at], &acct[at + 1..]);
+
+    if user.is_empty() || host.is_empty() || host.contains('/') {
+        None
+    } else {
+        Some((user, host))
+    }
+}
+
+/// Finds the ActivityPub actor ID in a WebFinger response.
+fn extract_self(jrd: &JValue) -> Option<String> {
+    jrd.get("links")?
+        .as_array()?
+        .iter()
+        .filter(|f| f.get("rel").and_then(JValue::as_str) == Some("self"))
+        .find(|f| match f.get("type").and_then(JValue::as_str) {
+            Some("application/activity+json") => true,
+            Some(val) => val.starts_with("application/ld+json"),
+            None => false,
+        })
+        .and_then(|f| f.get("href"))
+        .and_then(JValue::as_str)
+        .map(str::to_owned)
+}
+
+fn remember(resource: String, actor: Option<String>) {
+    if ACCOUNT_MAP.len() >= MAX_ACCOUNTS {
+        let now = Instant::now();
+        ACCOUNT_MAP.retain(|_, f| f.1 > now);
+
+        if ACCOUNT_MAP.len() >= MAX_ACCOUNTS {
+            ACCOUNT_MAP.clear();
+        }
+    }
+
+    let ttl = if actor.is_some() {
+        ACCOUNT_TTL
+    } else {
+        FAILURE_TTL
+    };
+    ACCOUNT_MAP.insert(resource, (actor, Instant::now() + ttl));
+}
+
+/// Asks the host of an account for its actor. The actor has to live on that same host,
+/// as the host could claim any actor otherwise.
+async fn lookup(
+    outbound: &Outbound,
+    resource: &str,
+    host: &str,
+) -> Result<Option<String>, StoreError> {
+    let url = format!(
+        "https://{}/.well-known/webfinger?resource={}",
+        host,
+        byte_serialize(resource.as_bytes()).collect::<String>()
+    );
+
+    let jrd = fetch_json(outbound, &url, "application/jrd+json, application/json").await?;
+    let actor = match extract_self(&jrd) {
+        Some(actor) => actor,
+        None => return Ok(None),
+    };
+
+    let host = host.split(':').next().unwrap_or(host).to_lowercase();
+    if federation::host_of(&actor) != Some(host) {
+        println!(" - {} resolved to {} on another host", resource, actor);
+        return Ok(None);
+    }
+
+    Ok(Some(actor))
+}
+
+/// Resolves an `acct:user@host` URI to the ID of the actor, using WebFinger.
+pub async fn resolve(outbound: &Outbound, acct: &str) -> Result<Option<String>, StoreError> {
+    let (user, host) = match split_acct(acct) {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+
+    let resource = format!("acct:{}@{}", user, host);
+    if let Some(ref cached) = ACCOUNT_MAP.get(&resource) {
+        if cached.1 > Instant::now() {
+            return Ok(cached.0.to_owned());
+        }
+    }
+
+    let actor = match lookup(outbound, &resource, host).await {
+        Ok(actor) => actor,
+        Err(e) => {
+            println!(" - failed to resolve {}: {}", resource, e);
+            None
+        }
+    };
+
+    remember(resource, actor.clone());
+
+    Ok(actor)
+}
+
 pub fn routes() -> Vec<Route> {
     vec![Route::get("/.well-known/webfinger", WebfingerHandler)]
 }
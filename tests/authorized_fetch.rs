@@ -0,0 +1,61 @@
+mod common;
+
+use async_std::task::block_on;
+use common::{send, Instance};
+use kroeg_server::client::{ClientRequest, ClientResponse};
+use kroeg_server::config::DomainPolicy;
+
+fn get(url: &str) -> ClientRequest {
+    http::Request::builder()
+        .uri(url)
+        .header("Accept", "application/activity+json")
+        .body(Vec::new())
+        .unwrap()
+}
+
+fn body(response: &ClientResponse) -> String {
+    String::from_utf8_lossy(response.body()).into_owned()
+}
+
+#[test]
+fn requires_signatures_for_everything_but_keys() {
+    block_on(async {
+        let a = Instance::start_with("secure-a", |config| {
+            config.federation.authorized_fetch = true;
+        })
+        .await;
+        let b = Instance::start("secure-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        // Without a signature, actors only show what is needed to verify their signatures.
+        let response = send(get(&alice.id)).await;
+        assert_eq!(response.status(), 200);
+        assert!(body(&response).contains(&alice.key_id));
+        assert!(body(&response).contains(&alice.inbox));
+        assert!(!body(&response).contains(&alice.outbox));
+
+        let response = send(get(&alice.key_id)).await;
+        assert_eq!(response.status(), 200);
+        assert!(body(&response).contains("BEGIN PUBLIC KEY"));
+
+        // Anything else isn't shown at all.
+        assert_eq!(send(get(&alice.outbox)).await.status(), 401);
+
+        // Signed requests from other servers see the whole actor.
+        let response = send(bob.sign(get(&alice.id))).await;
+        assert_eq!(response.status(), 200);
+        assert!(body(&response).contains(&alice.outbox));
+
+        // Unless their domain is blocked.
+        a.state.federation.update(|policy| {
+            policy
+                .domains
+                .insert("secure-b.test".to_owned(), DomainPolicy::Reject);
+        });
+
+        let response = send(bob.sign(get(&alice.id))).await;
+        assert_eq!(response.status(), 403);
+        assert!(!body(&response).contains(&alice.outbox));
+    });
+}
@@ -0,0 +1,645 @@
+//! An in-process federation of Kroeg instances, for end-to-end tests.
+//!
+//! Every instance has its own `MemoryStorePool` and domain, like `https://a.test`, and the
+//! `Network` routes requests between them in memory. It is the HTTP client of every instance.
+//! The JSON-LD contexts are preloaded from fixtures, so nothing goes to the real network.
+//!
+//! Deliveries don't happen in the background: call `Instance::deliver` to run the delivery
+//! queue of an instance. Tests run in parallel, so every test starts its own instances.
+
+#![allow(dead_code)]
+
+use http_service::{Body, HttpService};
+use jsonld::nodemap::{Pointer, Value};
+use kroeg_server::cache::CachingEntityStore;
+use kroeg_server::client::{ClientRequest, ClientResponse, HttpClient, RecordedRequest};
+use kroeg_server::config::{OutboundConfig, ServerConfig};
+use kroeg_server::delivery::{self, http_date, sign_request};
+use kroeg_server::get::GetHandler;
+use kroeg_server::memory::MemoryStorePool;
+use kroeg_server::post::PostHandler;
+use kroeg_server::request::FetchError;
+use kroeg_server::router::{RequestHandler, Route};
+use kroeg_server::state::ServiceState;
+use kroeg_server::store::RetrievingEntityStore;
+use kroeg_server::{
+    context, federation, instance, webfinger, KroegService, LeasedConnection, ServerError,
+};
+use kroeg_tap::{as2, kroeg, ldp, sec, Context, EntityStore, StoreError, StoreItem, User};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sign::Signer,
+};
+use serde_json::{json, Value as JValue};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+lazy_static::lazy_static! {
+    static ref NETWORK: Arc<Network> = Arc::new(Network::new());
+
+    static ref INSTANCE_IDS: AtomicU32 = AtomicU32::new(1);
+}
+
+/// Returns the network all instances are on.
+pub fn network() -> &'static Network {
+    &NETWORK
+}
+
+/// A response served for a URL instead of asking an instance.
+#[derive(Clone)]
+struct Prepared {
+    status: u16,
+    body: JValue,
+    delay: Duration,
+}
+
+/// Routes requests to the instances by host.
+pub struct Network {
+    instances: RwLock<HashMap<String, KroegService<MemoryStorePool>>>,
+    prepared: Mutex<HashMap<String, Prepared>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+impl Network {
+    fn new() -> Network {
+        for (url, fixture) in &[
+            (
+                "https://www.w3.org/ns/activitystreams",
+                include_str!("../fixtures/activitystreams.jsonld"),
+            ),
+            (
+                "https://w3id.org/security/v1",
+                include_str!("../fixtures/security-v1.jsonld"),
+            ),
+        ] {
+            context::preload(url, serde_json::from_str(fixture).unwrap());
+        }
+
+        Network {
+            instances: RwLock::new(HashMap::new()),
+            prepared: Mutex::new(HashMap::new()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Answers requests to a URL with a response, after a delay, like a remote server that
+    /// isn't a Kroeg instance would.
+    pub fn serve(&self, url: &str, status: u16, body: JValue, delay: Duration) {
+        self.prepared.lock().unwrap().insert(
+            url.to_owned(),
+            Prepared {
+                status,
+                body,
+                delay,
+            },
+        );
+    }
+
+    /// Returns the requests sent so far to a URL.
+    pub fn requests_to(&self, url: &str) -> Vec<RecordedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| f.url == url)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the requests sent so far to an instance.
+    pub fn requests_to_instance(&self, instance: &Instance) -> Vec<RecordedRequest> {
+        let prefix = format!("{}/", instance.domain);
+
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| f.url.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpClient for Network {
+    async fn send(
+        &self,
+        request: ClientRequest,
+        max_size: usize,
+    ) -> Result<ClientResponse, StoreError> {
+        self.requests.lock().unwrap().push(RecordedRequest {
+            method: request.method().clone(),
+            url: request.uri().to_string(),
+            headers: request.headers().clone(),
+            body: request.body().clone(),
+        });
+
+        let prepared = self
+            .prepared
+            .lock()
+            .unwrap()
+            .get(&request.uri().to_string())
+            .cloned();
+        if let Some(prepared) = prepared {
+            async_std::task::sleep(prepared.delay).await;
+
+            return Ok(http::Response::builder()
+                .status(prepared.status)
+                .header("Content-Type", "application/activity+json")
+                .body(prepared.body.to_string().into_bytes())
+                .unwrap());
+        }
+
+        let host = request.uri().host().unwrap_or("").to_owned();
+        let service = self.instances.read().unwrap().get(&host).cloned();
+        let service = match service {
+            Some(service) => service,
+            None => return Err(format!("no instance at {}", host).into()),
+        };
+
+        // Servers see the path and a Host header, not the full URL.
+        let (mut parts, body) = request.into_parts();
+        parts.headers.insert("host", host.parse()?);
+        parts.uri = parts
+            .uri
+            .path_and_query()
+            .map(|f| f.as_str())
+            .unwrap_or("/")
+            .parse()?;
+
+        let response = service
+            .respond(&mut (), http::Request::from_parts(parts, Body::from(body)))
+            .await?;
+
+        let (parts, body) = response.into_parts();
+        let body = body.into_vec().await?;
+        if body.len() > max_size {
+            return Err(FetchError::TooLarge.into());
+        }
+
+        Ok(http::Response::from_parts(parts, body))
+    }
+}
+
+/// Serves the context that is linked from all outgoing documents.
+struct ContextHandler;
+
+#[async_trait::async_trait]
+impl RequestHandler for ContextHandler {
+    async fn run(
+        &self,
+        _: &mut Context<'_, '_>,
+        _: http_service::Request,
+    ) -> Result<http_service::Response, ServerError> {
+        Ok(http::Response::builder()
+            .header("Content-Type", "application/ld+json")
+            .body(Body::from(context::read_context().to_string()))
+            .unwrap())
+    }
+}
+
+fn anonymous() -> User {
+    User {
+        claims: HashMap::new(),
+        issuer: None,
+        subject: "anonymous".to_owned(),
+        audience: vec![],
+        token_identifier: "anon".to_owned(),
+    }
+}
+
+/// One Kroeg instance on the network.
+pub struct Instance {
+    pub domain: String,
+    pub pool: MemoryStorePool,
+    pub config: ServerConfig,
+    pub state: Arc<ServiceState>,
+}
+
+impl Instance {
+    /// Starts an instance at `https://{name}.test`.
+    pub async fn start(name: &str) -> Instance {
+        Instance::start_with(name, |_| {}).await
+    }
+
+    /// Starts an instance, with changes to the default test configuration.
+    pub async fn start_with<F: FnOnce(&mut ServerConfig)>(name: &str, configure: F) -> Instance {
+        let domain = format!("https://{}.test", name);
+        let mut config = ServerConfig {
+            domain: domain.to_owned(),
+            name: name.to_owned(),
+            description: format!("The {} test instance", name),
+            instance_id: INSTANCE_IDS.fetch_add(1, Ordering::SeqCst),
+            admins: vec![],
+            fetch: Default::default(),
+            outbound: OutboundConfig {
+                allow_hosts: vec![
+                    "test".to_owned(),
+                    "www.w3.org".to_owned(),
+                    "w3id.org".to_owned(),
+                ],
+                ..Default::default()
+            },
+            federation: Default::default(),
+            cache: Default::default(),
+            post: Default::default(),
+            rate_limit: Default::default(),
+        };
+        configure(&mut config);
+
+        let mut routes = vec![
+            Route::get_prefix("/", GetHandler),
+            Route::post_prefix("/", PostHandler),
+            Route::get("/-/context", ContextHandler),
+        ];
+        routes.extend(webfinger::routes());
+        routes.extend(instance::routes());
+        routes.extend(federation::routes(&config));
+
+        let pool = MemoryStorePool::new();
+        let service = KroegService::new(pool.clone(), config.clone(), routes, NETWORK.clone())
+            .await
+            .unwrap();
+        let state = service.state();
+
+        NETWORK
+            .instances
+            .write()
+            .unwrap()
+            .insert(format!("{}.test", name), service);
+
+        let instance = Instance {
+            domain,
+            pool,
+            config,
+            state,
+        };
+
+        let shared_inbox = instance.shared_inbox();
+        instance
+            .put(
+                json!({ "@id": shared_inbox, "@type": [as2!(OrderedCollection)] }),
+                Some(as2!(sharedInbox)),
+            )
+            .await;
+
+        instance
+    }
+
+    pub fn shared_inbox(&self) -> String {
+        format!("{}/inbox", self.domain)
+    }
+
+    /// Parses an object, marked as owned by this instance.
+    fn owned(&self, json: &JValue) -> StoreItem {
+        let mut item = StoreItem::parse(json["@id"].as_str().unwrap(), json).unwrap();
+
+        item.meta()[kroeg!(instance)] = vec![Pointer::Value(Value {
+            value: json!(self.config.instance_id),
+            type_id: Some("http://www.w3.org/2001/XMLSchema#integer".to_owned()),
+            language: None,
+        })];
+
+        item
+    }
+
+    async fn store(&self, mut item: StoreItem) {
+        let mut connection = self.pool.connection();
+        let (store, _) = connection.get();
+
+        store.put(item.id().to_owned(), &mut item).await.unwrap();
+    }
+
+    /// Stores an object as owned by this instance, optionally as a box of a type.
+    pub async fn put(&self, json: JValue, box_type: Option<&str>) {
+        let mut item = self.owned(&json);
+
+        if let Some(box_type) = box_type {
+            item.meta()[kroeg!(box)] = vec![Pointer::Id(box_type.to_owned())];
+        }
+
+        self.store(item).await;
+    }
+
+    /// Creates a user with its boxes, collections and key.
+    pub async fn create_user(&self, name: &str) -> Actor {
+        let id = format!("{}/users/{}", self.domain, name);
+        let actor = Actor {
+            inbox: format!("{}/inbox", id),
+            outbox: format!("{}/outbox", id),
+            followers: format!("{}/followers", id),
+            following: format!("{}/following", id),
+            key_id: format!("{}/key", id),
+            private_key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            issuer: self.domain.to_owned(),
+            id,
+        };
+
+        let endpoints = format!("{}#endpoints", actor.id);
+
+        self.put(
+            json!({
+                "@id": actor.id,
+                "@type": [as2!(Person)],
+                as2!(preferredUsername): [{ "@value": name }],
+                ldp!(inbox): [{ "@id": actor.inbox }],
+                as2!(outbox): [{ "@id": actor.outbox }],
+                as2!(followers): [{ "@id": actor.followers }],
+                as2!(following): [{ "@id": actor.following }],
+                as2!(endpoints): [{ "@id": endpoints }],
+                sec!(publicKey): [{ "@id": actor.key_id }]
+            }),
+            None,
+        )
+        .await;
+
+        self.put(
+            json!({
+                "@id": endpoints,
+                as2!(sharedInbox): [{ "@id": self.shared_inbox() }]
+            }),
+            None,
+        )
+        .await;
+
+        for (collection, box_type) in &[
+            (&actor.inbox, Some(ldp!(inbox))),
+            (&actor.outbox, Some(as2!(outbox))),
+            (&actor.followers, None),
+            (&actor.following, None),
+        ] {
+            self.put(
+                json!({ "@id": collection, "@type": [as2!(OrderedCollection)] }),
+                *box_type,
+            )
+            .await;
+        }
+
+        self.store_key(&actor).await;
+
+        actor
+    }
+
+    /// Gives a user of this instance a new key under the same key ID, like a server
+    /// rotating its keys would. Other instances still have the old key stored.
+    pub async fn rotate_key(&self, actor: &mut Actor) {
+        actor.private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        self.store_key(actor).await;
+    }
+
+    async fn store_key(&self, actor: &Actor) {
+        let rsa = actor.private_key.rsa().unwrap();
+        let private_key_pem = String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap();
+
+        let mut key = self.owned(&json!({
+            "@id": actor.key_id,
+            "@type": [sec!(Key)],
+            sec!(owner): [{ "@id": actor.id }],
+            sec!(publicKeyPem): [{ "@value": actor.public_key_pem() }]
+        }));
+        key.meta()[sec!(privateKeyPem)] = vec![Pointer::Value(Value {
+            value: JValue::String(private_key_pem),
+            type_id: None,
+            language: None,
+        })];
+        self.store(key).await;
+    }
+
+    /// Reads an object from the store of this instance, without retrieving it.
+    pub async fn get(&self, id: &str) -> Option<StoreItem> {
+        let mut connection = self.pool.connection();
+        let (store, _) = connection.get();
+
+        store.get(id.to_owned(), true).await.unwrap()
+    }
+
+    /// Retrieves a remote object, like this instance would while handling a request.
+    pub async fn fetch(&self, id: &str) -> Option<StoreItem> {
+        self.fetch_all(&[id]).await.pop().unwrap()
+    }
+
+    /// Retrieves remote objects one after another, like this instance would while handling
+    /// a single request, so they share a fetch budget. Stale objects are queued for a
+    /// refresh afterwards.
+    pub async fn fetch_all(&self, ids: &[&str]) -> Vec<Option<StoreItem>> {
+        let mut connection = self.pool.connection();
+        let (store, queue) = connection.get();
+        let store = CachingEntityStore::new(store, self.state.cache.clone());
+        let mut store = RetrievingEntityStore::new(store, self.state.clone());
+
+        let mut items = Vec::new();
+        for id in ids {
+            items.push(store.get((*id).to_owned(), false).await.unwrap());
+        }
+
+        store.queue_stale(queue).await;
+
+        items
+    }
+
+    /// Returns the newest items of a collection.
+    pub async fn collection(&self, id: &str) -> Vec<String> {
+        let mut connection = self.pool.connection();
+        let (store, _) = connection.get();
+
+        store
+            .read_collection(id.to_owned(), Some(100), None)
+            .await
+            .unwrap()
+            .items
+    }
+
+    pub async fn insert(&self, collection: &str, id: &str) {
+        let mut connection = self.pool.connection();
+        let (store, _) = connection.get();
+
+        store
+            .insert_collection(collection.to_owned(), id.to_owned())
+            .await
+            .unwrap();
+    }
+
+    /// Runs the queue of this instance until it is empty, returning the number of items that
+    /// were handled. Stops at the first item that fails.
+    pub async fn deliver(&self) -> Result<usize, ServerError> {
+        let mut connection = self.pool.connection();
+        let (store, queue) = connection.get();
+        let store = CachingEntityStore::new(store, self.state.cache.clone());
+        let mut store = RetrievingEntityStore::new(store, self.state.clone());
+        let budget = store.budget();
+        let mut handled = 0;
+
+        while let Some(item) = queue.get_item().await.map_err(ServerError::StoreError)? {
+            budget.reset();
+
+            let result = {
+                let mut context = Context {
+                    server_base: self.config.domain.to_owned(),
+                    name: self.config.name.to_owned(),
+                    description: self.config.description.to_owned(),
+                    instance_id: self.config.instance_id,
+                    user: anonymous(),
+
+                    entity_store: &mut store,
+                    queue_store: &mut *queue,
+                };
+
+                let delivery = delivery::deliver_one(&mut context, &item, &budget);
+                context::with_outbound(self.state.outbound.clone(), delivery).await
+            };
+
+            match result {
+                Ok(()) => queue
+                    .mark_success(item)
+                    .await
+                    .map_err(ServerError::StoreError)?,
+
+                Err(e) => {
+                    queue
+                        .mark_failure(item)
+                        .await
+                        .map_err(ServerError::StoreError)?;
+
+                    return Err(e);
+                }
+            }
+
+            handled += 1;
+        }
+
+        Ok(handled)
+    }
+}
+
+/// A user on one of the instances, with the keys to act as them.
+pub struct Actor {
+    pub id: String,
+    pub inbox: String,
+    pub outbox: String,
+    pub followers: String,
+    pub following: String,
+    pub key_id: String,
+
+    issuer: String,
+    private_key: PKey<Private>,
+}
+
+impl Actor {
+    /// Creates a bearer token for this user, as used by clients posting to the outbox.
+    pub fn token(&self) -> String {
+        self.token_with_scope(None)
+    }
+
+    /// Creates a bearer token for this user, limited to a space-separated list of scopes.
+    pub fn token_with_scope(&self, scope: Option<&str>) -> String {
+        let encode = |value: JValue| {
+            base64::encode_config(value.to_string().as_bytes(), base64::URL_SAFE_NO_PAD)
+        };
+
+        let header = encode(json!({ "typ": "JWT", "alg": "RS256", "kid": self.key_id }));
+        let mut claims = json!({
+            "iss": self.issuer,
+            "sub": self.id,
+            "exp": u32::max_value()
+        });
+        if let Some(scope) = scope {
+            claims["scope"] = json!(scope);
+        }
+        let claims = encode(claims);
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key).unwrap();
+        signer.update(header.as_bytes()).unwrap();
+        signer.update(b".").unwrap();
+        signer.update(claims.as_bytes()).unwrap();
+        let signature =
+            base64::encode_config(&signer.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD);
+
+        format!("{}.{}.{}", header, claims, signature)
+    }
+
+    /// Adds a bearer token for this user to a request.
+    pub fn authorize(&self, request: ClientRequest) -> ClientRequest {
+        self.authorize_with_scope(None, request)
+    }
+
+    /// Adds a bearer token for this user to a request, limited to some scopes.
+    pub fn authorize_with_scope(
+        &self,
+        scope: Option<&str>,
+        mut request: ClientRequest,
+    ) -> ClientRequest {
+        let token = format!("Bearer {}", self.token_with_scope(scope));
+        request
+            .headers_mut()
+            .insert("authorization", token.parse().unwrap());
+
+        request
+    }
+
+    /// Signs a request with the key of this user, as a server delivering for them would.
+    pub fn sign(&self, request: ClientRequest) -> ClientRequest {
+        self.sign_as(&self.key_id, request)
+    }
+
+    /// Signs a request with the key of this user, but names another key ID in the signature.
+    pub fn sign_as(&self, key_id: &str, mut request: ClientRequest) -> ClientRequest {
+        request
+            .headers_mut()
+            .insert("date", http_date(SystemTime::now()).parse().unwrap());
+
+        sign_request(
+            key_id,
+            &self.private_key,
+            &["(request-target)", "host", "date"],
+            request,
+        )
+        .unwrap()
+    }
+
+    /// Returns the public key of this user, in PEM.
+    pub fn public_key_pem(&self) -> String {
+        String::from_utf8(self.private_key.public_key_to_pem().unwrap()).unwrap()
+    }
+}
+
+/// Builds a POST of an activity to a box.
+pub fn activity(url: &str, activity: JValue) -> ClientRequest {
+    http::Request::builder()
+        .method("POST")
+        .uri(url)
+        .header("Content-Type", "application/activity+json")
+        .body(activity.to_string().into_bytes())
+        .unwrap()
+}
+
+/// Sends a request over the network.
+pub async fn send(request: ClientRequest) -> ClientResponse {
+    NETWORK.send(request, usize::max_value()).await.unwrap()
+}
+
+/// Returns the URL in the Location header of a response.
+pub fn location(response: &ClientResponse) -> String {
+    response.headers()["location"].to_str().unwrap().to_owned()
+}
+
+/// Makes `follower` follow `followed`, as if they had gone through Follow and Accept.
+pub async fn follow(follower: (&Instance, &Actor), followed: (&Instance, &Actor)) {
+    let (follower_instance, follower) = follower;
+    let (followed_instance, followed) = followed;
+
+    followed_instance
+        .insert(&followed.followers, &follower.id)
+        .await;
+    follower_instance
+        .insert(&follower.following, &followed.id)
+        .await;
+
+    // Both instances have seen the other user by now.
+    follower_instance.fetch(&followed.id).await.unwrap();
+    followed_instance.fetch(&follower.id).await.unwrap();
+}
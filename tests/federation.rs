@@ -0,0 +1,995 @@
+mod common;
+
+use async_std::task::block_on;
+use common::{activity, follow, location, network, send, Actor, Instance};
+use jsonld::nodemap::Pointer;
+use kroeg_server::client::SurfClient;
+use kroeg_server::config::DomainPolicy;
+use kroeg_server::memory::MemoryStorePool;
+use kroeg_server::{instance, KroegService};
+use kroeg_tap::{as2, kroeg, sec};
+use serde_json::json;
+use std::sync::Arc;
+
+#[test]
+fn accepts_follows_across_instances() {
+    block_on(async {
+        let a = Instance::start("follow-a").await;
+        let b = Instance::start("follow-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        let response = send(bob.authorize(activity(
+            &bob.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Follow",
+                "actor": bob.id,
+                "object": alice.id,
+                "to": [alice.id]
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let follow = location(&response);
+        b.deliver().await.unwrap();
+        assert!(a.collection(&alice.inbox).await.contains(&follow));
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Accept",
+                "actor": alice.id,
+                "object": follow,
+                "to": [bob.id]
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let accept = location(&response);
+        a.deliver().await.unwrap();
+        b.deliver().await.unwrap();
+
+        assert!(b.collection(&bob.inbox).await.contains(&accept));
+        assert!(b.collection(&bob.following).await.contains(&alice.id));
+    });
+}
+
+#[test]
+fn delivers_posts_to_followers() {
+    block_on(async {
+        let a = Instance::start("posts-a").await;
+        let b = Instance::start("posts-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "hello from a",
+                    "to": [alice.followers]
+                }
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let create = location(&response);
+        a.deliver().await.unwrap();
+        b.deliver().await.unwrap();
+
+        assert!(b.collection(&bob.inbox).await.contains(&create));
+
+        let stored = b.get(&create).await.unwrap();
+        assert_eq!(stored.main().types, vec![as2!(Create).to_owned()]);
+    });
+}
+
+#[test]
+fn uses_shared_inboxes() {
+    block_on(async {
+        let a = Instance::start("shared-a").await;
+        let b = Instance::start("shared-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+        let carol = b.create_user("carol").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+        follow((&b, &carol), (&a, &alice)).await;
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "hello, both of you",
+                    "to": [alice.followers]
+                }
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let create = location(&response);
+        a.deliver().await.unwrap();
+
+        // One delivery to the shared inbox, which B then hands out to its users.
+        assert_eq!(network().requests_to(&b.shared_inbox()).len(), 1);
+        assert!(network().requests_to(&bob.inbox).is_empty());
+        assert!(network().requests_to(&carol.inbox).is_empty());
+
+        b.deliver().await.unwrap();
+
+        assert!(b.collection(&bob.inbox).await.contains(&create));
+        assert!(b.collection(&carol.inbox).await.contains(&create));
+    });
+}
+
+#[test]
+fn silences_domains_on_shared_inboxes() {
+    block_on(async {
+        let a = Instance::start("silence-a").await;
+        let b = Instance::start_with("silence-b", |config| {
+            config
+                .federation
+                .domains
+                .insert("silence-a.test".to_owned(), DomainPolicy::Silence);
+        })
+        .await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+        let carol = b.create_user("carol").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+        a.fetch(&carol.id).await.unwrap();
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers, carol.id],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "hello, follower or not",
+                    "to": [alice.followers, carol.id]
+                }
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let create = location(&response);
+        a.deliver().await.unwrap();
+        assert_eq!(network().requests_to(&b.shared_inbox()).len(), 1);
+
+        b.deliver().await.unwrap();
+
+        // Carol doesn't follow Alice, so her copy is silenced.
+        assert!(b.collection(&bob.inbox).await.contains(&create));
+        assert!(!b.collection(&carol.inbox).await.contains(&create));
+    });
+}
+
+#[test]
+fn checks_signatures_on_both_sides() {
+    block_on(async {
+        let a = Instance::start("signed-a").await;
+        let b = Instance::start("signed-b").await;
+        let alice = a.create_user("alice").await;
+        let mallory = a.create_user("mallory").await;
+        let bob = b.create_user("bob").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": { "type": "Note", "content": "signed", "to": [alice.followers] }
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+        a.deliver().await.unwrap();
+
+        // Deliveries are signed by their actor, retrievals by an instance actor.
+        for request in network().requests_to(&b.shared_inbox()) {
+            let signature = request.header("signature").unwrap();
+            assert!(signature.contains(&format!("keyId=\"{}\"", alice.key_id)));
+        }
+
+        for request in network()
+            .requests_to_instance(&a)
+            .into_iter()
+            .chain(network().requests_to_instance(&b))
+            .filter(|f| f.method == http::Method::GET)
+        {
+            assert!(request.header("signature").is_some(), "{}", request.url);
+        }
+
+        // Neither side accepts activities that aren't signed by their actor.
+        for (instance, inbox, actor) in &[(&b, &bob.inbox, &alice), (&a, &alice.inbox, &bob)] {
+            let forged = |id: &str| {
+                activity(
+                    inbox,
+                    json!({
+                        "@context": "https://www.w3.org/ns/activitystreams",
+                        "id": format!("{}/{}", actor.id, id),
+                        "type": "Create",
+                        "actor": actor.id,
+                        "object": { "type": "Note", "content": "forged" }
+                    }),
+                )
+            };
+
+            let unsigned = send(forged("unsigned")).await;
+            assert_eq!(unsigned.status(), 403);
+
+            let wrong_key = send(mallory.sign(forged("wrong-key"))).await;
+            assert_eq!(wrong_key.status(), 403);
+
+            let received = instance.collection(inbox).await;
+            assert!(!received.contains(&format!("{}/unsigned", actor.id)));
+            assert!(!received.contains(&format!("{}/wrong-key", actor.id)));
+        }
+    });
+}
+
+#[test]
+fn refuses_two_services_with_the_same_domain() {
+    block_on(async {
+        let a = Instance::start("twice").await;
+
+        let second = KroegService::new(
+            MemoryStorePool::new(),
+            a.config.clone(),
+            Vec::new(),
+            Arc::new(SurfClient),
+        )
+        .await;
+        assert!(second.is_err());
+    });
+}
+
+#[test]
+fn retrieves_contexts_as_the_instance_actor() {
+    block_on(async {
+        let a = Instance::start("context-a").await;
+        let b = Instance::start("context-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        let context = format!("{}/contexts/custom", a.domain);
+        send(alice.sign(activity(
+            &bob.inbox,
+            json!({
+                "@context": ["https://www.w3.org/ns/activitystreams", context],
+                "id": format!("{}/creates/1", alice.id),
+                "type": "Create",
+                "actor": alice.id,
+                "object": { "type": "Note", "content": "with a context of my own" }
+            }),
+        )))
+        .await;
+
+        // B retrieves the context through the network of the harness, as its instance actor.
+        let requests = network().requests_to(&context);
+        assert_eq!(requests.len(), 1);
+
+        let signature = requests[0].header("signature").unwrap();
+        assert!(signature.contains(&format!("keyId=\"{}\"", instance::key_id(&b.domain))));
+    });
+}
+
+#[test]
+fn refuses_keys_that_claim_other_owners() {
+    block_on(async {
+        let a = Instance::start("owners-a").await;
+        let b = Instance::start("owners-b").await;
+        let c = Instance::start("owners-c").await;
+        let alice = a.create_user("alice").await;
+        let mallory = a.create_user("mallory").await;
+        let bob = b.create_user("bob").await;
+        let eve = c.create_user("eve").await;
+
+        // Keys with Mallory's and Eve's public key that name Alice as their owner, one on
+        //  Alice's instance and one on another.
+        let on_same_host = format!("{}/keys/mallory", a.domain);
+        let on_other_host = format!("{}/keys/eve", c.domain);
+        for (instance, key_id, signer) in
+            &[(&a, &on_same_host, &mallory), (&c, &on_other_host, &eve)]
+        {
+            instance
+                .put(
+                    json!({
+                        "@id": key_id,
+                        "@type": [sec!(Key)],
+                        sec!(owner): [{ "@id": alice.id }],
+                        sec!(publicKeyPem): [{ "@value": signer.public_key_pem() }]
+                    }),
+                    None,
+                )
+                .await;
+        }
+
+        for (key_id, signer, id) in &[
+            (&on_same_host, &mallory, "same-host"),
+            (&on_other_host, &eve, "other-host"),
+        ] {
+            let forged = activity(
+                &bob.inbox,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "id": format!("{}/{}", alice.id, id),
+                    "type": "Create",
+                    "actor": alice.id,
+                    "object": { "type": "Note", "content": "forged" }
+                }),
+            );
+
+            let response = send(signer.sign_as(key_id, forged)).await;
+            assert_eq!(response.status(), 403);
+            assert!(!b
+                .collection(&bob.inbox)
+                .await
+                .contains(&format!("{}/{}", alice.id, id)));
+        }
+    });
+}
+
+#[test]
+fn refetches_keys_after_rotation() {
+    block_on(async {
+        let a = Instance::start("rotate-a").await;
+        let b = Instance::start("rotate-b").await;
+        let c = Instance::start("rotate-c").await;
+        let alice = a.create_user("alice").await;
+        let mut bob = b.create_user("bob").await;
+        let mallory = c.create_user("mallory").await;
+
+        // Bob's key is replaced halfway, so this can't borrow him.
+        let bob_id = bob.id.clone();
+        let create = |signer: &Actor, key_id: &str, name: &str| {
+            signer.sign_as(
+                key_id,
+                activity(
+                    &alice.inbox,
+                    json!({
+                        "@context": "https://www.w3.org/ns/activitystreams",
+                        "id": format!("{}/{}", bob_id, name),
+                        "type": "Create",
+                        "actor": bob_id,
+                        "object": { "type": "Note", "content": name }
+                    }),
+                ),
+            )
+        };
+
+        let response = send(create(&bob, &bob.key_id, "before")).await;
+        assert_eq!(response.status(), 201);
+        assert_eq!(network().requests_to(&bob.key_id).len(), 1);
+
+        // The stored key no longer matches, so it is retrieved again once.
+        b.rotate_key(&mut bob).await;
+
+        let response = send(create(&bob, &bob.key_id, "after")).await;
+        assert_eq!(response.status(), 201);
+        assert_eq!(network().requests_to(&bob.key_id).len(), 2);
+        assert!(a
+            .collection(&alice.inbox)
+            .await
+            .contains(&format!("{}/after", bob.id)));
+
+        let stored = a.get(&bob.key_id).await.unwrap();
+        match &stored.main()[sec!(publicKeyPem)] as &[Pointer] {
+            [Pointer::Value(value)] => assert_eq!(value.value, json!(bob.public_key_pem())),
+            _ => panic!("Key has no public key"),
+        }
+
+        // Bad signatures can't make it retrieve the key over and over.
+        let response = send(create(&mallory, &bob.key_id, "forged")).await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(network().requests_to(&bob.key_id).len(), 2);
+        assert!(!a
+            .collection(&alice.inbox)
+            .await
+            .contains(&format!("{}/forged", bob.id)));
+    });
+}
+
+#[test]
+fn resolves_acct_key_ids_through_webfinger() {
+    block_on(async {
+        let a = Instance::start("acct-a").await;
+        let b = Instance::start("acct-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        let acct = format!("acct:bob@{}", b.domain.trim_start_matches("https://"));
+        let create = |name: &str| {
+            bob.sign_as(
+                &acct,
+                activity(
+                    &alice.inbox,
+                    json!({
+                        "@context": "https://www.w3.org/ns/activitystreams",
+                        "id": format!("{}/{}", bob.id, name),
+                        "type": "Create",
+                        "actor": bob.id,
+                        "object": { "type": "Note", "content": name }
+                    }),
+                ),
+            )
+        };
+
+        let webfinger = || {
+            network()
+                .requests_to_instance(&b)
+                .into_iter()
+                .filter(|f| f.url.contains("/.well-known/webfinger"))
+                .count()
+        };
+
+        assert_eq!(send(create("first")).await.status(), 201);
+        assert_eq!(webfinger(), 1);
+        assert_eq!(network().requests_to(&bob.key_id).len(), 1);
+
+        // The account is remembered, and so is the key it resolved to.
+        assert_eq!(send(create("second")).await.status(), 201);
+        assert_eq!(webfinger(), 1);
+        assert_eq!(network().requests_to(&bob.key_id).len(), 1);
+
+        let received = a.collection(&alice.inbox).await;
+        assert!(received.contains(&format!("{}/first", bob.id)));
+        assert!(received.contains(&format!("{}/second", bob.id)));
+    });
+}
+
+#[test]
+fn ignores_repeated_deliveries() {
+    block_on(async {
+        let a = Instance::start("retry-a").await;
+        let b = Instance::start("retry-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+
+        let response = send(alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": { "type": "Note", "content": "once", "to": [alice.followers] }
+            }),
+        )))
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let create = location(&response);
+        a.deliver().await.unwrap();
+        b.deliver().await.unwrap();
+
+        // Send the exact same delivery again, as a server that missed the response would.
+        let delivered = network().requests_to(&b.shared_inbox()).remove(0);
+        let mut retry = activity(&delivered.url, json!(null));
+        *retry.headers_mut() = delivered.headers;
+        *retry.body_mut() = delivered.body;
+
+        let response = send(retry).await;
+        assert_eq!(response.status(), 200);
+        b.deliver().await.unwrap();
+
+        let received = b.collection(&bob.inbox).await;
+        assert_eq!(received.iter().filter(|f| **f == create).count(), 1);
+    });
+}
+
+#[test]
+fn deduplicates_deliveries_without_id() {
+    block_on(async {
+        let a = Instance::start("no-id-a").await;
+        let b = Instance::start("no-id-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        let delivery = || {
+            alice.sign(activity(
+                &bob.inbox,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Create",
+                    "actor": alice.id,
+                    "object": { "type": "Note", "content": "who am I?" }
+                }),
+            ))
+        };
+
+        // Delivered twice, like a server retrying it would, which is recognized by the digest.
+        assert_eq!(send(delivery()).await.status(), 201);
+        assert_eq!(send(delivery()).await.status(), 200);
+
+        let inbox = b.collection(&bob.inbox).await;
+        assert_eq!(inbox.len(), 1);
+        assert!(inbox[0].starts_with(&format!("_:{}#", alice.id)));
+    });
+}
+
+#[test]
+fn replays_outbox_posts_with_the_same_idempotency_key() {
+    block_on(async {
+        let a = Instance::start("idempotent-a").await;
+        let alice = a.create_user("alice").await;
+
+        let post = |key: &str| {
+            let mut request = alice.authorize(activity(
+                &alice.outbox,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Note",
+                    "content": "posted from a bad connection"
+                }),
+            ));
+            request
+                .headers_mut()
+                .insert("idempotency-key", key.parse().unwrap());
+
+            request
+        };
+
+        let first = send(post("one")).await;
+        let retried = send(post("one")).await;
+        let other = send(post("two")).await;
+
+        assert_eq!(first.status(), 201);
+        assert_eq!(retried.status(), 201);
+        assert_eq!(location(&retried), location(&first));
+        assert_ne!(location(&other), location(&first));
+
+        assert_eq!(a.collection(&alice.outbox).await.len(), 2);
+    });
+}
+
+#[test]
+fn refuses_large_and_non_activity_bodies() {
+    block_on(async {
+        let a = Instance::start("limits-a").await;
+        let alice = a.create_user("alice").await;
+
+        let large = activity(
+            &alice.inbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Note",
+                "content": "a".repeat(2 * 1024 * 1024)
+            }),
+        );
+        assert_eq!(send(large).await.status(), 413);
+
+        let mut form = activity(&alice.inbox, json!({ "type": "Note" }));
+        form.headers_mut().insert(
+            "content-type",
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+        assert_eq!(send(form).await.status(), 415);
+
+        let mut json = alice.authorize(activity(
+            &alice.outbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Note",
+                "content": "posted as plain JSON"
+            }),
+        ));
+        json.headers_mut()
+            .insert("content-type", "application/json".parse().unwrap());
+        assert_eq!(send(json).await.status(), 201);
+    });
+}
+
+/// Posts an activity to the outbox of `actor`, and delivers it everywhere.
+async fn publish(
+    from: &Instance,
+    to: &Instance,
+    actor: &Actor,
+    activity_json: serde_json::Value,
+) -> String {
+    let response = send(actor.authorize(activity(&actor.outbox, activity_json))).await;
+    assert_eq!(response.status(), 201);
+
+    from.deliver().await.unwrap();
+    to.deliver().await.unwrap();
+
+    location(&response)
+}
+
+#[test]
+fn handles_deletes() {
+    block_on(async {
+        let a = Instance::start("delete-a").await;
+        let b = Instance::start("delete-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+
+        let mut notes = Vec::new();
+        for content in &["first", "second"] {
+            let create = publish(
+                &a,
+                &b,
+                &alice,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Create",
+                    "actor": alice.id,
+                    "to": [alice.followers],
+                    "object": {
+                        "type": "Note",
+                        "attributedTo": alice.id,
+                        "content": content,
+                        "to": [alice.followers]
+                    }
+                }),
+            )
+            .await;
+
+            let note = match &b.get(&create).await.unwrap().main()[as2!(object)] as &[Pointer] {
+                [Pointer::Id(note)] => note.to_owned(),
+                _ => panic!("Create has no object"),
+            };
+
+            notes.push((create, note));
+        }
+
+        let (create, note) = notes.remove(0);
+        publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Delete",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": note
+            }),
+        )
+        .await;
+
+        let deleted = b.get(&note).await.unwrap();
+        assert_eq!(deleted.main().types, vec![as2!(Tombstone).to_owned()]);
+        assert!(!b.collection(&bob.inbox).await.contains(&create));
+
+        // Deleting themselves takes everything else of the actor with them.
+        let (create, note) = notes.remove(0);
+        publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Delete",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": alice.id
+            }),
+        )
+        .await;
+
+        for id in &[&note, &alice.id] {
+            let deleted = b.get(id).await.unwrap();
+            assert_eq!(deleted.main().types, vec![as2!(Tombstone).to_owned()]);
+        }
+
+        assert!(!b.collection(&bob.inbox).await.contains(&create));
+        assert!(!b.collection(&bob.following).await.contains(&alice.id));
+    });
+}
+
+#[test]
+fn handles_deletes_delivered_to_several_inboxes() {
+    block_on(async {
+        let a = Instance::start("repeated-delete-a").await;
+        let b = Instance::start("repeated-delete-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+        let carol = b.create_user("carol").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+        follow((&b, &carol), (&a, &alice)).await;
+
+        let create = publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "going away",
+                    "to": [alice.followers]
+                }
+            }),
+        )
+        .await;
+
+        let note = match &b.get(&create).await.unwrap().main()[as2!(object)] as &[Pointer] {
+            [Pointer::Id(note)] => note.to_owned(),
+            _ => panic!("Create has no object"),
+        };
+
+        // The same Delete arrives at both inboxes, and the note is gone after the first.
+        let delete = format!("{}/deletes/1", alice.id);
+        for inbox in &[&bob.inbox, &carol.inbox] {
+            let response = send(alice.sign(activity(
+                inbox,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "id": delete,
+                    "type": "Delete",
+                    "actor": alice.id,
+                    "object": note
+                }),
+            )))
+            .await;
+            assert_eq!(response.status(), 201);
+            assert!(b.collection(inbox).await.contains(&delete));
+        }
+
+        let deleted = b.get(&note).await.unwrap();
+        assert_eq!(deleted.main().types, vec![as2!(Tombstone).to_owned()]);
+        match &deleted.main()[as2!(attributedTo)] as &[Pointer] {
+            [Pointer::Id(owner)] => assert_eq!(owner, &alice.id),
+            _ => panic!("Tombstone has no owner"),
+        }
+    });
+}
+
+#[test]
+fn refuses_deletes_of_objects_of_others() {
+    block_on(async {
+        let a = Instance::start("foreign-delete-a").await;
+        let b = Instance::start("foreign-delete-b").await;
+        let c = Instance::start("foreign-delete-c").await;
+        let alice = a.create_user("alice").await;
+        let carol = b.create_user("carol").await;
+        let bob = c.create_user("bob").await;
+
+        follow((&b, &carol), (&a, &alice)).await;
+        follow((&b, &carol), (&c, &bob)).await;
+
+        let mut notes = Vec::new();
+        for (instance, actor) in &[(&a, &alice), (&c, &bob)] {
+            let create = publish(
+                instance,
+                &b,
+                actor,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Create",
+                    "actor": actor.id,
+                    "to": [actor.followers],
+                    "object": {
+                        "type": "Note",
+                        "attributedTo": actor.id,
+                        "content": "mine",
+                        "to": [actor.followers]
+                    }
+                }),
+            )
+            .await;
+
+            match &b.get(&create).await.unwrap().main()[as2!(object)] as &[Pointer] {
+                [Pointer::Id(note)] => notes.push(note.to_owned()),
+                _ => panic!("Create has no object"),
+            }
+        }
+
+        // Bob deletes his own note and Alice's at once, which is refused as a whole.
+        let response = send(bob.sign(activity(
+            &carol.inbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": format!("{}/deletes/1", bob.id),
+                "type": "Delete",
+                "actor": bob.id,
+                "object": [notes[1], notes[0]]
+            }),
+        )))
+        .await;
+        assert!(response.status().is_success());
+
+        for note in &notes {
+            let stored = b.get(note).await.unwrap();
+            assert_eq!(stored.main().types, vec![as2!(Note).to_owned()]);
+        }
+    });
+}
+
+/// Reads the content of a stored Note.
+fn content(note: &kroeg_tap::StoreItem) -> String {
+    match &note.main()[as2!(content)] as &[Pointer] {
+        [Pointer::Value(value)] => value.value.as_str().unwrap().to_owned(),
+        _ => panic!("Note has no content"),
+    }
+}
+
+#[test]
+fn handles_updates() {
+    block_on(async {
+        let a = Instance::start("update-a").await;
+        let b = Instance::start("update-b").await;
+        let alice = a.create_user("alice").await;
+        let bob = b.create_user("bob").await;
+
+        follow((&b, &bob), (&a, &alice)).await;
+
+        let create = publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "original",
+                    "to": [alice.followers]
+                }
+            }),
+        )
+        .await;
+
+        let note = match &b.get(&create).await.unwrap().main()[as2!(object)] as &[Pointer] {
+            [Pointer::Id(note)] => note.to_owned(),
+            _ => panic!("Create has no object"),
+        };
+
+        // Edit the note on A, which embeds the new version when it delivers the Update.
+        a.put(
+            json!({
+                "@id": note,
+                "@type": [as2!(Note)],
+                as2!(attributedTo): [{ "@id": alice.id }],
+                as2!(content): [{ "@value": "edited" }],
+                as2!(to): [{ "@id": alice.followers }]
+            }),
+            None,
+        )
+        .await;
+
+        publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Update",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": note
+            }),
+        )
+        .await;
+
+        let stored = b.get(&note).await.unwrap();
+        assert_eq!(content(&stored), "edited");
+
+        let revisions = match &stored.main()[kroeg!(revisions)] as &[Pointer] {
+            [Pointer::Id(revisions)] => revisions.to_owned(),
+            _ => panic!("Note has no revisions"),
+        };
+        assert!(revisions.starts_with(&b.domain));
+
+        let items = b.collection(&revisions).await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(content(&b.get(&items[0]).await.unwrap()), "original");
+
+        // The revisions are served by B, as their IDs are its own.
+        for id in &[&revisions, &items[0]] {
+            let request = http::Request::builder()
+                .method("GET")
+                .uri(id.as_str())
+                .header("Accept", "application/activity+json")
+                .body(Vec::new())
+                .unwrap();
+            assert_eq!(send(bob.authorize(request)).await.status(), 200);
+        }
+    });
+}
+
+#[test]
+fn refuses_updates_of_objects_of_others() {
+    block_on(async {
+        let a = Instance::start("foreign-update-a").await;
+        let b = Instance::start("foreign-update-b").await;
+        let c = Instance::start("foreign-update-c").await;
+        let alice = a.create_user("alice").await;
+        let carol = b.create_user("carol").await;
+        let bob = c.create_user("bob").await;
+
+        follow((&b, &carol), (&a, &alice)).await;
+        follow((&b, &carol), (&c, &bob)).await;
+
+        let create = publish(
+            &a,
+            &b,
+            &alice,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Create",
+                "actor": alice.id,
+                "to": [alice.followers],
+                "object": {
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "mine",
+                    "to": [alice.followers]
+                }
+            }),
+        )
+        .await;
+
+        let note = match &b.get(&create).await.unwrap().main()[as2!(object)] as &[Pointer] {
+            [Pointer::Id(note)] => note.to_owned(),
+            _ => panic!("Create has no object"),
+        };
+
+        // Bob claims a new version of Alice's note.
+        let response = send(bob.sign(activity(
+            &carol.inbox,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": format!("{}/updates/1", bob.id),
+                "type": "Update",
+                "actor": bob.id,
+                "object": {
+                    "id": note,
+                    "type": "Note",
+                    "attributedTo": alice.id,
+                    "content": "not mine",
+                    "to": [alice.followers]
+                }
+            }),
+        )))
+        .await;
+        assert!(response.status().is_success());
+
+        let stored = b.get(&note).await.unwrap();
+        assert_eq!(content(&stored), "mine");
+        assert!(stored.main()[kroeg!(revisions)].is_empty());
+    });
+}
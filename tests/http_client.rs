@@ -0,0 +1,208 @@
+use async_std::task::block_on;
+use http::{Method, StatusCode};
+use kroeg_server::client::{self, MockClient, MockResponse};
+use kroeg_server::config::OutboundConfig;
+use kroeg_server::memory::MemoryStorePool;
+use kroeg_server::request::{self, FetchError, Outbound};
+use kroeg_server::{instance, LeasedConnection};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    /// All tests share one client, each using their own URLs.
+    static ref CLIENT: MockClient = MockClient::new();
+}
+
+fn outbound() -> Outbound {
+    Outbound {
+        client: Arc::new(CLIENT.clone()),
+        policy: OutboundConfig {
+            allow_hosts: vec!["example".to_owned()],
+            max_response_size: 1024,
+            ..Default::default()
+        },
+        key: None,
+    }
+}
+
+fn fetch_json(url: &str, accept: &str) -> Result<Value, kroeg_tap::StoreError> {
+    block_on(request::fetch_json(&outbound(), url, accept))
+}
+
+fn respond(url: &str, response: MockResponse) {
+    CLIENT.respond(Method::GET, url, response);
+}
+
+fn fetch_error(url: &str) -> FetchError {
+    let error = fetch_json(url, "application/json").unwrap_err();
+
+    match error.downcast::<FetchError>() {
+        Ok(error) => *error,
+        Err(error) => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn fetches_json() {
+    respond(
+        "https://a.example/note",
+        MockResponse::json(&json!({ "id": "https://a.example/note" })),
+    );
+
+    let value = fetch_json("https://a.example/note", "application/json").unwrap();
+
+    assert_eq!(value, json!({ "id": "https://a.example/note" }));
+
+    let requests = CLIENT.requests_to("https://a.example/note");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].header("accept"), Some("application/json"));
+}
+
+#[test]
+fn follows_redirects() {
+    respond(
+        "https://a.example/old",
+        MockResponse::new(301).header("Location", "/new"),
+    );
+    respond(
+        "https://a.example/new",
+        MockResponse::json(&json!({ "id": "https://a.example/new" })),
+    );
+
+    let value = fetch_json("https://a.example/old", "application/json").unwrap();
+
+    assert_eq!(value, json!({ "id": "https://a.example/new" }));
+}
+
+#[test]
+fn stops_redirect_loops() {
+    respond(
+        "https://a.example/loop",
+        MockResponse::new(302).header("Location", "https://a.example/loop"),
+    );
+
+    match fetch_error("https://a.example/loop") {
+        FetchError::TooManyRedirects => {}
+        error => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn refuses_redirects_to_blocked_urls() {
+    respond(
+        "https://a.example/internal",
+        MockResponse::new(302).header("Location", "http://a.example/plain"),
+    );
+
+    match fetch_error("https://a.example/internal") {
+        FetchError::Blocked(url, _) => assert_eq!(url, "http://a.example/plain"),
+        error => panic!("unexpected error: {}", error),
+    }
+
+    assert!(CLIENT.requests_to("http://a.example/plain").is_empty());
+}
+
+#[test]
+fn reports_error_statuses() {
+    respond("https://a.example/gone", MockResponse::new(410));
+
+    match fetch_error("https://a.example/gone") {
+        FetchError::Status(StatusCode::GONE) => {}
+        error => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn rejects_other_content_types() {
+    respond(
+        "https://a.example/html",
+        MockResponse::new(200)
+            .header("Content-Type", "text/html")
+            .body("<p>hi</p>"),
+    );
+
+    match fetch_error("https://a.example/html") {
+        FetchError::BadContentType(kind) => assert_eq!(kind, "text/html"),
+        error => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn rejects_large_responses() {
+    respond(
+        "https://a.example/large",
+        MockResponse::json(&json!({ "content": "a".repeat(2048) })),
+    );
+
+    match fetch_error("https://a.example/large") {
+        FetchError::TooLarge => {}
+        error => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn signs_requests_as_instance_actor() {
+    let pool = MemoryStorePool::new();
+    let mut connection = pool.connection();
+    let (store, _) = connection.get();
+
+    let key = block_on(instance::load(store, "https://local.example", 0)).unwrap();
+    let outbound = Outbound {
+        key: Some(key),
+        ..outbound()
+    };
+
+    respond(
+        "https://a.example/secure",
+        MockResponse::json(&json!({ "id": "https://a.example/secure" })),
+    );
+
+    block_on(request::fetch_json(
+        &outbound,
+        "https://a.example/secure",
+        "application/json",
+    ))
+    .unwrap();
+
+    let request = CLIENT.requests_to("https://a.example/secure").remove(0);
+    let signature = request.header("signature").unwrap();
+
+    assert!(request.header("date").is_some());
+    assert!(signature.contains("keyId=\"https://local.example/-/actor#main-key\""));
+    assert!(signature.contains("headers=\"(request-target) host date\""));
+}
+
+#[test]
+fn replays_recorded_responses() {
+    let path = std::env::temp_dir().join(format!("kroeg-fixture-{}.json", std::process::id()));
+
+    let remote = MockClient::new();
+    remote.respond(
+        Method::GET,
+        "https://b.example/actor",
+        MockResponse::json(&json!({ "id": "https://b.example/actor" })),
+    );
+
+    let recording = MockClient::recording(Arc::new(remote));
+    let request = || {
+        http::Request::builder()
+            .uri("https://b.example/actor")
+            .body(Vec::new())
+            .unwrap()
+    };
+
+    let recorded = block_on(client::HttpClient::send(&recording, request(), 1024)).unwrap();
+    recording.save(&path).unwrap();
+
+    let replay = MockClient::replay(&path).unwrap();
+    let replayed = block_on(client::HttpClient::send(&replay, request(), 1024)).unwrap();
+
+    assert_eq!(replayed.status(), recorded.status());
+    assert_eq!(replayed.body(), recorded.body());
+    assert_eq!(
+        replayed.headers().get("content-type"),
+        recorded.headers().get("content-type")
+    );
+
+    let _ = std::fs::remove_file(&path);
+}
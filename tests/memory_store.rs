@@ -0,0 +1,227 @@
+use async_std::task::block_on;
+use kroeg_server::memory::MemoryStorePool;
+use kroeg_server::{LeasedConnection, StorePool};
+use kroeg_tap::{EntityStore, QuadQuery, QueryId, QueryObject, QueueStore, StoreItem};
+use serde_json::json;
+
+const AS2: &str = "https://www.w3.org/ns/activitystreams#";
+
+fn as2(name: &str) -> String {
+    format!("{}{}", AS2, name)
+}
+
+fn person(id: &str, name: &str, following: &str) -> StoreItem {
+    StoreItem::parse(
+        id,
+        &json!({
+            "@id": id,
+            "@type": [as2("Person")],
+            as2("preferredUsername"): [{ "@value": name }],
+            as2("following"): [{ "@id": following }]
+        }),
+    )
+    .unwrap()
+}
+
+async fn put(store: &mut dyn EntityStore, mut item: StoreItem) {
+    store.put(item.id().to_owned(), &mut item).await.unwrap();
+}
+
+#[test]
+fn stores_and_reads_objects() {
+    block_on(async {
+        let pool = MemoryStorePool::new();
+        let mut connection = pool.connect().await.unwrap();
+        let (store, _) = connection.get();
+
+        assert!(store
+            .get("https://a/u".to_owned(), true)
+            .await
+            .unwrap()
+            .is_none());
+
+        put(store, person("https://a/u", "u", "https://a/u/following")).await;
+
+        // Other connections see the same data.
+        let mut other = pool.connection();
+        let (other, _) = other.get();
+        let item = other
+            .get("https://a/u".to_owned(), true)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(item.id(), "https://a/u");
+        assert_eq!(item.main().types, vec![as2("Person")]);
+    });
+}
+
+#[test]
+fn answers_quad_queries() {
+    block_on(async {
+        let pool = MemoryStorePool::new();
+        let mut connection = pool.connection();
+        let (store, _) = connection.get();
+
+        put(store, person("https://a/u", "u", "https://a/u/following")).await;
+        put(store, person("https://a/v", "v", "https://a/v/following")).await;
+
+        let by_name = store
+            .query(vec![QuadQuery(
+                QueryId::Placeholder(0),
+                QueryId::Value(as2("preferredUsername")),
+                QueryObject::Object {
+                    value: "v".to_owned(),
+                    type_id: QueryId::Value("http://www.w3.org/2001/XMLSchema#string".to_owned()),
+                },
+            )])
+            .await
+            .unwrap();
+
+        assert_eq!(by_name, vec![vec!["https://a/v".to_owned()]]);
+
+        let mut joined = store
+            .query(vec![
+                QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value(as2("following")),
+                    QueryObject::Id(QueryId::Any(vec![
+                        "https://a/u/following".to_owned(),
+                        "https://a/v/following".to_owned(),
+                    ])),
+                ),
+                QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value(as2("preferredUsername")),
+                    QueryObject::Object {
+                        value: "u".to_owned(),
+                        type_id: QueryId::Placeholder(1),
+                    },
+                ),
+            ])
+            .await
+            .unwrap();
+        joined.sort();
+
+        assert_eq!(
+            joined,
+            vec![vec![
+                "https://a/u".to_owned(),
+                "http://www.w3.org/2001/XMLSchema#string".to_owned()
+            ]]
+        );
+    });
+}
+
+#[test]
+fn pages_through_collections() {
+    block_on(async {
+        let pool = MemoryStorePool::new();
+        let mut connection = pool.connection();
+        let (store, _) = connection.get();
+
+        for i in 0..5 {
+            store
+                .insert_collection("https://a/c".to_owned(), format!("https://a/{}", i))
+                .await
+                .unwrap();
+        }
+
+        let first = store
+            .read_collection("https://a/c".to_owned(), Some(2), None)
+            .await
+            .unwrap();
+
+        assert_eq!(first.items, vec!["https://a/4", "https://a/3"]);
+        assert_eq!(first.before, None);
+        assert_eq!(first.count, Some(5));
+
+        let second = store
+            .read_collection("https://a/c".to_owned(), Some(2), first.after)
+            .await
+            .unwrap();
+
+        assert_eq!(second.items, vec!["https://a/2", "https://a/1"]);
+
+        let last = store
+            .read_collection("https://a/c".to_owned(), Some(2), second.after.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(last.items, vec!["https://a/0"]);
+        assert_eq!(last.after, None);
+
+        let back = store
+            .read_collection("https://a/c".to_owned(), Some(2), last.before)
+            .await
+            .unwrap();
+
+        assert_eq!(back.items, second.items);
+    });
+}
+
+#[test]
+fn finds_and_removes_collection_items() {
+    block_on(async {
+        let pool = MemoryStorePool::new();
+        let mut connection = pool.connection();
+        let (store, _) = connection.get();
+
+        for collection in &["https://a/c", "https://a/d"] {
+            store
+                .insert_collection(collection.to_string(), "https://a/x".to_owned())
+                .await
+                .unwrap();
+        }
+
+        let found = store
+            .find_collection("https://a/c".to_owned(), "https://a/x".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(found.items, vec!["https://a/x"]);
+
+        let mut inverse = store
+            .read_collection_inverse("https://a/x".to_owned())
+            .await
+            .unwrap()
+            .items;
+        inverse.sort();
+        assert_eq!(inverse, vec!["https://a/c", "https://a/d"]);
+
+        store
+            .remove_collection("https://a/c".to_owned(), "https://a/x".to_owned())
+            .await
+            .unwrap();
+
+        let found = store
+            .find_collection("https://a/c".to_owned(), "https://a/x".to_owned())
+            .await
+            .unwrap();
+        assert!(found.items.is_empty());
+    });
+}
+
+#[test]
+fn retries_failed_queue_items() {
+    block_on(async {
+        let pool = MemoryStorePool::new();
+        let mut connection = pool.connection();
+        let (_, queue) = connection.get();
+
+        queue
+            .add("deliver".to_owned(), "a b".to_owned())
+            .await
+            .unwrap();
+
+        let item = queue.get_item().await.unwrap().unwrap();
+        assert_eq!(item.event, "deliver");
+        assert_eq!(item.data, "a b");
+        assert!(queue.get_item().await.unwrap().is_none());
+
+        queue.mark_failure(item).await.unwrap();
+        let item = queue.get_item().await.unwrap().unwrap();
+        queue.mark_success(item).await.unwrap();
+
+        assert!(queue.get_item().await.unwrap().is_none());
+    });
+}
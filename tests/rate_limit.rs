@@ -0,0 +1,98 @@
+mod common;
+
+use async_std::task::block_on;
+use common::{activity, network, send, Instance};
+use kroeg_server::config::RateLimit;
+use serde_json::json;
+
+#[test]
+fn limits_requests_by_client_and_user() {
+    block_on(async {
+        let a = Instance::start_with("limited", |config| {
+            config.rate_limit.ip_header = "X-Forwarded-For".to_owned();
+            config.rate_limit.read = RateLimit {
+                burst: 2,
+                per_minute: 1,
+            };
+            config.rate_limit.outbox = RateLimit {
+                burst: 1,
+                per_minute: 1,
+            };
+        })
+        .await;
+        let alice = a.create_user("alice").await;
+
+        let get = |ip: &str| {
+            http::Request::builder()
+                .uri(alice.id.as_str())
+                .header("X-Forwarded-For", format!("10.0.0.1, {}", ip))
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        assert_eq!(send(get("192.0.2.1")).await.status(), 200);
+        assert_eq!(send(get("192.0.2.1")).await.status(), 200);
+
+        let refused = send(get("192.0.2.1")).await;
+        assert_eq!(refused.status(), 429);
+
+        let retry_after: u64 = refused.headers()["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after >= 1 && retry_after <= 60);
+
+        // Other clients have their own budget.
+        assert_eq!(send(get("192.0.2.2")).await.status(), 200);
+
+        // Clients without an address aren't limited, as they would all share one bucket.
+        let anonymous = || {
+            http::Request::builder()
+                .uri(alice.id.as_str())
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        for _ in 0..3 {
+            assert_eq!(send(anonymous()).await.status(), 200);
+        }
+
+        // Signed requests are limited by the domain they claim, before the signature is
+        //  verified, so made up signatures can't make us retrieve keys without limit.
+        let mallory = |n: u32| {
+            let mut request = get("192.0.2.3");
+            request.headers_mut().remove("X-Forwarded-For");
+            let signature = format!(
+                "keyId=\"https://mallory.example/keys/{}\",headers=\"date\",signature=\"AAAA\"",
+                n
+            );
+            request
+                .headers_mut()
+                .insert("Signature", signature.parse().unwrap());
+
+            request
+        };
+
+        send(mallory(1)).await;
+        send(mallory(2)).await;
+        assert_eq!(send(mallory(3)).await.status(), 429);
+        assert!(network()
+            .requests_to("https://mallory.example/keys/3")
+            .is_empty());
+
+        let post = || {
+            alice.authorize(activity(
+                &alice.outbox,
+                json!({
+                    "@context": "https://www.w3.org/ns/activitystreams",
+                    "type": "Note",
+                    "content": "hi"
+                }),
+            ))
+        };
+
+        assert_eq!(send(post()).await.status(), 201);
+        assert_eq!(send(post()).await.status(), 429);
+    });
+}
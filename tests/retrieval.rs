@@ -0,0 +1,202 @@
+mod common;
+
+use async_std::task::{block_on, sleep};
+use common::{network, Instance};
+use futures::future::join3;
+use jsonld::nodemap::Pointer;
+use kroeg_tap::{as2, StoreItem};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// Stores a public Note on an instance, optionally in reply to another one.
+async fn note(origin: &Instance, name: &str, in_reply_to: Option<&str>) -> String {
+    let id = format!("{}/notes/{}", origin.domain, name);
+    let mut json = json!({
+        "@id": id,
+        "@type": [as2!(Note)],
+        as2!(content): [{ "@value": name }],
+        as2!(to): [{ "@id": as2!(Public) }]
+    });
+
+    if let Some(in_reply_to) = in_reply_to {
+        json[as2!(inReplyTo)] = json!([{ "@id": in_reply_to }]);
+    }
+
+    origin.put(json, None).await;
+
+    id
+}
+
+/// Reads the content of a stored Note.
+fn content(note: &StoreItem) -> String {
+    match &note.main()[as2!(content)] as &[Pointer] {
+        [Pointer::Value(value)] => value.value.as_str().unwrap().to_owned(),
+        _ => panic!("Note has no content"),
+    }
+}
+
+#[test]
+fn limits_how_deep_and_how_much_is_retrieved() {
+    block_on(async {
+        let origin = Instance::start("budget-origin").await;
+        let a = Instance::start_with("budget-a", |config| {
+            config.fetch.max_depth = 1;
+            config.fetch.max_fetches = 3;
+        })
+        .await;
+
+        // A thread, where every note replies to the next one.
+        let third = note(&origin, "third", None).await;
+        let second = note(&origin, "second", Some(&third)).await;
+        let first = note(&origin, "first", Some(&second)).await;
+
+        let fetched = a.fetch_all(&[&first, &second, &third]).await;
+        assert!(fetched[0].is_some());
+        assert!(fetched[1].is_some());
+
+        // The third note is two references away from the first one.
+        assert!(fetched[2].is_none());
+        assert!(network().requests_to(&third).is_empty());
+
+        // Unrelated notes are only retrieved until the budget runs out.
+        let mut others = Vec::new();
+        for name in &["one", "two", "three", "four"] {
+            others.push(note(&origin, name, None).await);
+        }
+
+        let ids: Vec<&str> = others.iter().map(String::as_str).collect();
+        let fetched = a.fetch_all(&ids).await;
+        assert_eq!(fetched.iter().filter(|f| f.is_some()).count(), 3);
+        assert!(fetched[3].is_none());
+        assert!(network().requests_to(&others[3]).is_empty());
+    });
+}
+
+#[test]
+fn cancels_retrievals_that_take_too_long() {
+    block_on(async {
+        let a = Instance::start_with("budget-time", |config| {
+            config.fetch.max_duration = 1;
+        })
+        .await;
+
+        let slow = "https://slow.test/notes/1";
+        network().serve(
+            slow,
+            200,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": slow,
+                "type": "Note",
+                "content": "eventually"
+            }),
+            Duration::from_secs(5),
+        );
+
+        let started = Instant::now();
+        assert!(a.fetch(slow).await.is_none());
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        // Running out of time says nothing about the remote server, so it is tried again.
+        assert!(a.fetch(slow).await.is_none());
+        assert_eq!(network().requests_to(slow).len(), 2);
+    });
+}
+
+#[test]
+fn shares_concurrent_retrievals_of_the_same_object() {
+    block_on(async {
+        let a = Instance::start("coalesce-a").await;
+        let b = Instance::start("coalesce-b").await;
+
+        let popular = "https://popular.test/notes/1";
+        network().serve(
+            popular,
+            200,
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": popular,
+                "type": "Note",
+                "content": "everyone wants this"
+            }),
+            Duration::from_millis(200),
+        );
+
+        let (first, second, third) =
+            join3(a.fetch(popular), a.fetch(popular), a.fetch(popular)).await;
+        assert!(first.is_some() && second.is_some() && third.is_some());
+        assert_eq!(network().requests_to(popular).len(), 1);
+
+        // Other services have their own stores, so they retrieve it themselves.
+        assert!(b.fetch(popular).await.is_some());
+        assert_eq!(network().requests_to(popular).len(), 2);
+    });
+}
+
+#[test]
+fn refreshes_stale_objects_through_the_queue() {
+    block_on(async {
+        let origin = Instance::start("stale-origin").await;
+        let a = Instance::start_with("stale-a", |config| {
+            config.fetch.ttl.insert("Note".to_owned(), 2);
+        })
+        .await;
+
+        let id = note(&origin, "before", None).await;
+        assert!(a.fetch(&id).await.is_some());
+        assert_eq!(a.deliver().await.unwrap(), 0);
+
+        sleep(Duration::from_secs(3)).await;
+        origin
+            .put(
+                json!({
+                    "@id": id,
+                    "@type": [as2!(Note)],
+                    as2!(content): [{ "@value": "after" }],
+                    as2!(to): [{ "@id": as2!(Public) }]
+                }),
+                None,
+            )
+            .await;
+
+        // Stale objects are still returned as stored, and only queued once for a refresh.
+        assert_eq!(content(&a.fetch(&id).await.unwrap()), "before");
+        assert_eq!(content(&a.fetch(&id).await.unwrap()), "before");
+        assert_eq!(network().requests_to(&id).len(), 1);
+
+        assert_eq!(a.deliver().await.unwrap(), 1);
+        assert_eq!(network().requests_to(&id).len(), 2);
+        assert_eq!(content(&a.get(&id).await.unwrap()), "after");
+    });
+}
+
+#[test]
+fn backs_off_from_objects_that_failed_to_be_retrieved() {
+    block_on(async {
+        let a = Instance::start("failing-a").await;
+        let b = Instance::start("failing-b").await;
+
+        let broken = "https://broken.test/notes/1";
+        network().serve(broken, 500, json!({}), Duration::from_secs(0));
+
+        assert!(a.fetch(broken).await.is_none());
+        assert!(a.fetch(broken).await.is_none());
+        assert_eq!(network().requests_to(broken).len(), 1);
+        assert!(a.get(broken).await.is_none());
+
+        // Every service backs off by itself.
+        assert!(b.fetch(broken).await.is_none());
+        assert_eq!(network().requests_to(broken).len(), 2);
+
+        // Objects that are gone are remembered as such.
+        let gone = "https://broken.test/notes/2";
+        network().serve(gone, 410, json!({}), Duration::from_secs(0));
+
+        assert!(a.fetch(gone).await.is_some());
+        assert!(a.fetch(gone).await.is_some());
+        assert_eq!(network().requests_to(gone).len(), 1);
+
+        let stored = a.get(gone).await.unwrap();
+        assert_eq!(stored.main().types, vec![as2!(Tombstone).to_owned()]);
+    });
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use async_std::task::block_on;
+use common::{activity, send, Instance};
+use kroeg_server::client::ClientResponse;
+use serde_json::json;
+
+fn body(response: &ClientResponse) -> String {
+    String::from_utf8_lossy(response.body()).into_owned()
+}
+
+#[test]
+fn requires_the_admin_scope_for_admin_routes() {
+    block_on(async {
+        let a = Instance::start_with("scoped", |config| {
+            config.admins = vec!["https://scoped.test/users/alice".to_owned()];
+        })
+        .await;
+        let alice = a.create_user("alice").await;
+        let bob = a.create_user("bob").await;
+
+        let policy = format!("{}/-/admin/federation", a.domain);
+        let get = || {
+            http::Request::builder()
+                .uri(policy.as_str())
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        let change = || {
+            http::Request::builder()
+                .method("POST")
+                .uri(policy.as_str())
+                .header("Content-Type", "application/json")
+                .body(
+                    json!({ "blocked_domains": ["evil.test"] })
+                        .to_string()
+                        .into_bytes(),
+                )
+                .unwrap()
+        };
+
+        // Tokens without the admin scope are refused before the handler runs.
+        for scope in &["read", "read write:outbox write:inbox"] {
+            let response = send(alice.authorize_with_scope(Some(scope), get())).await;
+            assert_eq!(response.status(), 403);
+            assert!(body(&response).contains("scope"));
+
+            let response = send(alice.authorize_with_scope(Some(scope), change())).await;
+            assert_eq!(response.status(), 403);
+            assert!(!a.state.federation.is_rejected("evil.test"));
+        }
+
+        // The scope alone doesn't make someone an admin.
+        let response = send(bob.authorize_with_scope(Some("admin"), change())).await;
+        assert_eq!(response.status(), 403);
+        assert!(!a.state.federation.is_rejected("evil.test"));
+
+        let response = send(alice.authorize_with_scope(Some("admin"), get())).await;
+        assert_eq!(response.status(), 200);
+
+        let response = send(alice.authorize_with_scope(Some("admin"), change())).await;
+        assert_eq!(response.status(), 200);
+        assert!(a.state.federation.is_rejected("evil.test"));
+    });
+}
+
+#[test]
+fn requires_the_write_scope_for_outboxes() {
+    block_on(async {
+        let a = Instance::start("scoped-outbox").await;
+        let alice = a.create_user("alice").await;
+
+        let post = |scope| {
+            alice.authorize_with_scope(
+                Some(scope),
+                activity(
+                    &alice.outbox,
+                    json!({
+                        "@context": "https://www.w3.org/ns/activitystreams",
+                        "type": "Note",
+                        "content": "scoped"
+                    }),
+                ),
+            )
+        };
+
+        assert_eq!(send(post("read")).await.status(), 403);
+        assert!(a.collection(&alice.outbox).await.is_empty());
+
+        assert_eq!(send(post("read write:outbox")).await.status(), 201);
+        assert_eq!(a.collection(&alice.outbox).await.len(), 1);
+    });
+}
@@ -0,0 +1,328 @@
+use http::Request;
+use kroeg_server::signature::{from_request, parse, parse_params, Signature, SignatureError};
+
+const MASTODON: &str = "keyId=\"https://example.com/users/a#main-key\",algorithm=\"rsa-sha256\",headers=\"(request-target) host date digest\",signature=\"YWJjZA==\"";
+
+fn params(input: &str) -> Vec<(String, String)> {
+    parse_params(input).unwrap()
+}
+
+fn pair(name: &str, value: &str) -> (String, String) {
+    (name.to_owned(), value.to_owned())
+}
+
+#[test]
+fn parses_mastodon_header() {
+    assert_eq!(
+        parse(MASTODON),
+        Ok(Signature {
+            key_id: "https://example.com/users/a#main-key".to_owned(),
+            algorithm: Some("rsa-sha256".to_owned()),
+            headers: vec![
+                "(request-target)".to_owned(),
+                "host".to_owned(),
+                "date".to_owned(),
+                "digest".to_owned()
+            ],
+            signature: b"abcd".to_vec(),
+        })
+    );
+}
+
+#[test]
+fn parses_tokens_and_whitespace() {
+    assert_eq!(
+        params(" a=b ,\tc = \"d\" , e=f"),
+        vec![pair("a", "b"), pair("c", "d"), pair("e", "f")]
+    );
+}
+
+#[test]
+fn parses_commas_and_equals_in_quoted_strings() {
+    assert_eq!(
+        params("keyId=\"https://example.com/?a=b,c=d\",x=\"\""),
+        vec![pair("keyId", "https://example.com/?a=b,c=d"), pair("x", "")]
+    );
+}
+
+#[test]
+fn parses_escapes_in_quoted_strings() {
+    assert_eq!(
+        params(r#"a="quote \" backslash \\ letter \q""#),
+        vec![pair("a", r#"quote " backslash \ letter q"#)]
+    );
+}
+
+#[test]
+fn keeps_non_ascii_in_quoted_strings() {
+    assert_eq!(params("a=\"kroeg 🍺\""), vec![pair("a", "kroeg 🍺")]);
+}
+
+#[test]
+fn defaults_headers_to_date() {
+    let signature = parse("keyId=\"a\",signature=\"YWJjZA==\"").unwrap();
+
+    assert_eq!(signature.headers, vec!["date".to_owned()]);
+    assert_eq!(signature.algorithm, None);
+}
+
+#[test]
+fn lowercases_and_collapses_headers() {
+    let signature = parse("keyId=a,signature=\"YWJjZA==\",headers=\"Host  Date\"").unwrap();
+
+    assert_eq!(
+        signature.headers,
+        vec!["host".to_owned(), "date".to_owned()]
+    );
+}
+
+#[test]
+fn ignores_unknown_parameters() {
+    let signature = parse("keyId=a,created=1,expires=\"2\",signature=\"YWJjZA==\"").unwrap();
+
+    assert_eq!(signature.key_id, "a");
+}
+
+#[test]
+fn rejects_empty_headers() {
+    assert_eq!(parse(""), Err(SignatureError::Empty));
+    assert_eq!(parse("   "), Err(SignatureError::Empty));
+    assert_eq!(parse("\t"), Err(SignatureError::Empty));
+}
+
+#[test]
+fn rejects_missing_names() {
+    assert_eq!(parse("=a"), Err(SignatureError::ExpectedName(0)));
+    assert_eq!(parse(",a=b"), Err(SignatureError::ExpectedName(0)));
+    assert_eq!(parse("a=b,,c=d"), Err(SignatureError::ExpectedName(4)));
+    assert_eq!(parse("\"a\"=b"), Err(SignatureError::ExpectedName(0)));
+}
+
+#[test]
+fn rejects_trailing_commas() {
+    assert_eq!(parse("a=b,"), Err(SignatureError::ExpectedName(4)));
+    assert_eq!(parse("a=b, "), Err(SignatureError::ExpectedName(5)));
+}
+
+#[test]
+fn rejects_missing_equals() {
+    assert_eq!(parse("keyId"), Err(SignatureError::ExpectedEquals(5)));
+    assert_eq!(parse("keyId \"a\""), Err(SignatureError::ExpectedEquals(6)));
+    assert_eq!(parse("a=b,keyId"), Err(SignatureError::ExpectedEquals(9)));
+    assert_eq!(parse("key:Id=a"), Err(SignatureError::ExpectedEquals(3)));
+}
+
+#[test]
+fn rejects_missing_values() {
+    assert_eq!(parse("a="), Err(SignatureError::ExpectedValue(2)));
+    assert_eq!(parse("a=,b=c"), Err(SignatureError::ExpectedValue(2)));
+    assert_eq!(parse("a= "), Err(SignatureError::ExpectedValue(3)));
+    assert_eq!(parse("a=(b)"), Err(SignatureError::ExpectedValue(2)));
+}
+
+#[test]
+fn rejects_missing_commas() {
+    assert_eq!(parse("a=b c=d"), Err(SignatureError::ExpectedComma(4)));
+    assert_eq!(parse("a=\"b\"c=d"), Err(SignatureError::ExpectedComma(5)));
+    assert_eq!(parse("a=b;c=d"), Err(SignatureError::ExpectedComma(3)));
+    assert_eq!(parse("a=b=c"), Err(SignatureError::ExpectedComma(3)));
+}
+
+#[test]
+fn rejects_unterminated_strings() {
+    assert_eq!(parse("a=\""), Err(SignatureError::UnterminatedString(2)));
+    assert_eq!(parse("a=\"bc"), Err(SignatureError::UnterminatedString(2)));
+    assert_eq!(
+        parse("a=\"b\\\""),
+        Err(SignatureError::UnterminatedString(2))
+    );
+    assert_eq!(parse("a=\"b\\"), Err(SignatureError::UnterminatedString(2)));
+    assert_eq!(
+        parse("a=b,c=\"d,e=f"),
+        Err(SignatureError::UnterminatedString(6))
+    );
+}
+
+#[test]
+fn rejects_control_characters() {
+    assert_eq!(
+        parse("a=\"b\nc\""),
+        Err(SignatureError::InvalidCharacter(4))
+    );
+    assert_eq!(
+        parse("a=\"b\u{0}\""),
+        Err(SignatureError::InvalidCharacter(4))
+    );
+    assert_eq!(
+        parse("a=\"\u{7f}\""),
+        Err(SignatureError::InvalidCharacter(3))
+    );
+    assert_eq!(
+        parse("a=\"\\\r\""),
+        Err(SignatureError::InvalidCharacter(4))
+    );
+    assert_eq!(params("a=\"b\tc\""), vec![pair("a", "b\tc")]);
+}
+
+#[test]
+fn rejects_duplicate_parameters() {
+    assert_eq!(
+        parse("keyId=a,keyId=b,signature=\"YWJjZA==\""),
+        Err(SignatureError::DuplicateParameter("keyId".to_owned()))
+    );
+    assert_eq!(
+        parse("signature=a,signature=\"a\""),
+        Err(SignatureError::DuplicateParameter("signature".to_owned()))
+    );
+}
+
+#[test]
+fn rejects_missing_parameters() {
+    assert_eq!(
+        parse("signature=\"YWJjZA==\""),
+        Err(SignatureError::MissingParameter("keyId"))
+    );
+    assert_eq!(
+        parse("keyId=\"a\""),
+        Err(SignatureError::MissingParameter("signature"))
+    );
+    assert_eq!(
+        parse("keyid=\"a\",signature=\"YWJjZA==\""),
+        Err(SignatureError::MissingParameter("keyId"))
+    );
+}
+
+#[test]
+fn rejects_empty_header_lists() {
+    assert_eq!(
+        parse("keyId=a,signature=\"YWJjZA==\",headers=\"\""),
+        Err(SignatureError::EmptyHeaders)
+    );
+    assert_eq!(
+        parse("keyId=a,signature=\"YWJjZA==\",headers=\"   \""),
+        Err(SignatureError::EmptyHeaders)
+    );
+}
+
+#[test]
+fn rejects_bad_signatures() {
+    assert_eq!(
+        parse("keyId=a,signature=\"not base64!\""),
+        Err(SignatureError::BadSignature)
+    );
+    assert_eq!(
+        parse("keyId=a,signature=\"YW=JjZA==\""),
+        Err(SignatureError::BadSignature)
+    );
+    assert_eq!(
+        parse("keyId=a,signature=\"\""),
+        Err(SignatureError::BadSignature)
+    );
+}
+
+#[test]
+fn never_panics_on_truncated_input() {
+    for end in 0..=MASTODON.len() {
+        let _ = parse(&MASTODON[..end]);
+    }
+}
+
+#[test]
+fn never_panics_on_garbage() {
+    let inputs = [
+        "\"",
+        "\\",
+        "=",
+        ",",
+        "==",
+        "a==b",
+        "a=\"\\",
+        "\u{feff}a=b",
+        "a=b,\u{1F37A}=c",
+        "a=\u{1F37A}",
+        ",,,,,,",
+        "\"\"\"\"",
+    ];
+
+    for input in &inputs {
+        assert!(parse(input).is_err(), "{:?} should not parse", input);
+    }
+}
+
+#[test]
+fn reads_signature_header() {
+    let (parts, _) = Request::builder()
+        .header("Signature", MASTODON)
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), Some(parse(MASTODON)));
+}
+
+#[test]
+fn reads_authorization_header() {
+    let (parts, _) = Request::builder()
+        .header("Authorization", format!("Signature {}", MASTODON))
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), Some(parse(MASTODON)));
+
+    let (parts, _) = Request::builder()
+        .header("Authorization", format!("signature   {}", MASTODON))
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), Some(parse(MASTODON)));
+}
+
+#[test]
+fn ignores_other_authorization() {
+    let (parts, _) = Request::builder()
+        .header("Authorization", "Bearer a.b.c")
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), None);
+
+    let (parts, _) = Request::builder()
+        .header("Authorization", "Signatures keyId=a")
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), None);
+
+    let (parts, _) = Request::builder().body(()).unwrap().into_parts();
+
+    assert_eq!(from_request(&parts), None);
+}
+
+#[test]
+fn rejects_empty_authorization_signature() {
+    let (parts, _) = Request::builder()
+        .header("Authorization", "Signature")
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(from_request(&parts), Some(Err(SignatureError::Empty)));
+}
+
+#[test]
+fn rejects_non_ascii_header_values() {
+    let (parts, _) = Request::builder()
+        .header("Signature", &b"keyId=\"\xff\""[..])
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    assert_eq!(
+        from_request(&parts),
+        Some(Err(SignatureError::InvalidHeader))
+    );
+}
@@ -0,0 +1,185 @@
+#![cfg(feature = "sqlite")]
+
+use async_std::task::block_on;
+use kroeg_server::sqlite::SqliteStorePool;
+use kroeg_server::LeasedConnection;
+use kroeg_tap::{EntityStore, QuadQuery, QueryId, QueryObject, QueueStore, StoreItem};
+use serde_json::json;
+use std::path::PathBuf;
+
+const AS2: &str = "https://www.w3.org/ns/activitystreams#";
+
+fn as2(name: &str) -> String {
+    format!("{}{}", AS2, name)
+}
+
+fn database(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("kroeg-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    path
+}
+
+fn note(id: &str, content: &str) -> StoreItem {
+    StoreItem::parse(
+        id,
+        &json!({
+            "@id": id,
+            "@type": [as2("Note")],
+            as2("content"): [{ "@value": content }],
+            as2("attributedTo"): [{ "@id": "https://a/u" }]
+        }),
+    )
+    .unwrap()
+}
+
+#[test]
+fn keeps_data_between_opens() {
+    let path = database("reopen");
+
+    block_on(async {
+        let pool = SqliteStorePool::open(&path).unwrap();
+        let mut connection = pool.connection();
+        let (store, queue) = connection.get();
+
+        let mut item = note("https://a/n", "hello");
+        store
+            .put("https://a/n".to_owned(), &mut item)
+            .await
+            .unwrap();
+        queue
+            .add("deliver".to_owned(), "a b".to_owned())
+            .await
+            .unwrap();
+    });
+
+    block_on(async {
+        // Opening again must not run the migrations twice.
+        let pool = SqliteStorePool::open(&path).unwrap();
+        let mut connection = pool.connection();
+        let (store, queue) = connection.get();
+
+        let item = store
+            .get("https://a/n".to_owned(), true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.main().types, vec![as2("Note")]);
+
+        let queued = queue.get_item().await.unwrap().unwrap();
+        assert_eq!(queued.data, "a b");
+    });
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn answers_quad_queries() {
+    block_on(async {
+        let pool = SqliteStorePool::open_in_memory().unwrap();
+        let mut connection = pool.connection();
+        let (store, _) = connection.get();
+
+        for (id, content) in &[("https://a/1", "one"), ("https://a/2", "two")] {
+            let mut item = note(id, content);
+            store.put(id.to_string(), &mut item).await.unwrap();
+        }
+
+        // Replacing an object replaces its quads.
+        let mut item = note("https://a/2", "three");
+        store
+            .put("https://a/2".to_owned(), &mut item)
+            .await
+            .unwrap();
+
+        let query = |content: &str| {
+            vec![
+                QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value(as2("content")),
+                    QueryObject::Object {
+                        value: content.to_owned(),
+                        type_id: QueryId::Placeholder(1),
+                    },
+                ),
+                QuadQuery(
+                    QueryId::Placeholder(0),
+                    QueryId::Value(as2("attributedTo")),
+                    QueryObject::Id(QueryId::Any(vec!["https://a/u".to_owned()])),
+                ),
+            ]
+        };
+
+        assert_eq!(
+            store.query(query("one")).await.unwrap(),
+            vec![vec![
+                "https://a/1".to_owned(),
+                "http://www.w3.org/2001/XMLSchema#string".to_owned()
+            ]]
+        );
+        assert!(store.query(query("two")).await.unwrap().is_empty());
+        assert_eq!(store.query(query("three")).await.unwrap().len(), 1);
+    });
+}
+
+#[test]
+fn pages_through_collections() {
+    block_on(async {
+        let pool = SqliteStorePool::open_in_memory().unwrap();
+        let mut connection = pool.connection();
+        let (store, _) = connection.get();
+
+        for i in 0..5 {
+            store
+                .insert_collection("https://a/c".to_owned(), format!("https://a/{}", i))
+                .await
+                .unwrap();
+        }
+
+        let first = store
+            .read_collection("https://a/c".to_owned(), Some(2), None)
+            .await
+            .unwrap();
+        assert_eq!(first.items, vec!["https://a/4", "https://a/3"]);
+        assert_eq!(first.before, None);
+        assert_eq!(first.count, Some(5));
+
+        let second = store
+            .read_collection("https://a/c".to_owned(), Some(2), first.after)
+            .await
+            .unwrap();
+        assert_eq!(second.items, vec!["https://a/2", "https://a/1"]);
+
+        let back = store
+            .read_collection("https://a/c".to_owned(), Some(2), second.before)
+            .await
+            .unwrap();
+        assert_eq!(back.items, vec!["https://a/4", "https://a/3"]);
+
+        let inverse = store
+            .read_collection_inverse("https://a/3".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(inverse.items, vec!["https://a/c"]);
+    });
+}
+
+#[test]
+fn delays_failed_queue_items() {
+    block_on(async {
+        let pool = SqliteStorePool::open_in_memory().unwrap();
+        let mut connection = pool.connection();
+        let (_, queue) = connection.get();
+
+        queue
+            .add("deliver".to_owned(), "a b".to_owned())
+            .await
+            .unwrap();
+
+        let item = queue.get_item().await.unwrap().unwrap();
+        assert!(queue.get_item().await.unwrap().is_none());
+
+        queue.mark_failure(item).await.unwrap();
+        assert!(queue.get_item().await.unwrap().is_none());
+    });
+}